@@ -0,0 +1,573 @@
+// Bidirectional path tracing: build one subpath from the camera and one from an emitter, then join
+// every prefix of one to every prefix of the other. Each join is a different strategy for sampling
+// the same path, and multiple importance sampling weights them so each path is mostly found by the
+// strategies suited to it: light through a small opening is easy to follow from the light, but near
+// hopeless to hit from the camera. Joining a light subpath straight to the camera (light tracing)
+// lands on an arbitrary pixel, so those contributions are splatted onto the film.
+//
+// Emitters are the only lights subpaths start from. Point, spot and directional lights are sampled
+// from every camera vertex as in the path tracer, which is the only way they can be reached.
+// REF: Veach, "Robust Monte Carlo Methods for Light Transport Simulation", PhD thesis, 1997, chapter 10
+// REF: Pharr, Jakob and Humphreys, "Physically Based Rendering", 3rd ed., section 16.3
+
+use std::f64::consts::PI;
+
+use cgmath::{Point2, Point3, Vector3, InnerSpace, MetricSpace};
+
+use crate::{Color, Emitters, Intersectable, Ray, Scene};
+use crate::bsdf::{cosine_hemisphere, Bsdf};
+use crate::integrator::Integrator;
+use crate::path_tracer::delta_lighting;
+use crate::sampler::Sampler;
+
+pub struct BidirectionalPathTracer;
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,      // the start of a light subpath, on an emitter
+    Surface,
+}
+
+struct Vertex<'a> {
+    kind: VertexKind,
+    point: Point3<f64>,
+    normal: Vector3<f64>,   // the surface normal; for the camera, its view direction
+    wo: Vector3<f64>,       // towards the previous vertex of the subpath
+    bsdf: Option<Bsdf>,
+    object: Option<&'a dyn Intersectable>,
+    throughput: Color,      // the subpath's contribution up to here over the density of sampling it
+    pdf_fwd: f64,           // area density of sampling this vertex from the previous one on its subpath
+    pdf_rev: f64,           // area density of sampling it from the next one, as the other subpath would
+    delta: bool,            // scattered by glass or a mirror, so can't be joined to
+}
+
+// What's fixed for every path of a render: the emitters, and whether the camera can be projected onto
+struct Context<'a> {
+    scene: &'a Scene,
+    emitters: Emitters<'a>,
+    light_tracing: bool,
+    max_depth: usize,
+}
+
+impl<'a> Vertex<'a> {
+    fn on_surface(&self) -> bool {
+        self.kind != VertexKind::Camera
+    }
+
+    // Can another subpath be joined on here? Not at a glass or mirror surface, which only scatters
+    // light into directions it picks itself.
+    fn is_connectible(&self) -> bool {
+        match &self.bsdf {
+            Some(bsdf) => bsdf.is_rough(),
+            None => true,
+        }
+    }
+
+    // The light scattered at this vertex from `next` back along the subpath
+    fn f(&self, next: &Vertex) -> Color {
+        match &self.bsdf {
+            Some(bsdf) => bsdf.evaluate(self.wo, (next.point - self.point).normalize()),
+            None => Color::black(),
+        }
+    }
+
+    fn emission(&self) -> Color {
+        self.object.map_or(Color::black(), |object| object.material().emission)
+    }
+
+    // Turn a solid angle density of leaving this vertex into an area density at `next`
+    fn to_area(&self, pdf: f64, next: &Vertex) -> f64 {
+        let offset = next.point - self.point;
+        let distance2 = offset.magnitude2();
+        if distance2 == 0.0 {
+            return 0.0;
+        }
+        let cosine = if next.on_surface() { next.normal.dot(offset).abs() / distance2.sqrt() } else { 1.0 };
+        pdf * cosine / distance2
+    }
+
+    // The area density of sampling `next` from this vertex, having arrived from `previous`
+    fn pdf(&self, context: &Context, previous: Option<&Vertex>, next: &Vertex) -> f64 {
+        let direction = (next.point - self.point).normalize();
+        let pdf = match self.kind {
+            VertexKind::Light => return self.pdf_light(next),
+            VertexKind::Camera => context.camera_pdf(direction),
+            VertexKind::Surface => {
+                let bsdf = self.bsdf.as_ref().unwrap();
+                match previous {
+                    Some(previous) => bsdf.pdf((previous.point - self.point).normalize(), direction),
+                    None => 0.0,
+                }
+            }
+        };
+        self.to_area(pdf, next)
+    }
+
+    // The area density of an emitter at this vertex sending its light to `next`
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let direction = (next.point - self.point).normalize();
+        let cosine = self.normal.dot(direction);
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        self.to_area(cosine / PI, next)
+    }
+
+    // The area density of a light subpath starting at this vertex, on an emitter
+    fn pdf_light_origin(&self, context: &Context) -> f64 {
+        match self.object {
+            Some(object) if !context.emitters.is_empty() => 1.0 / (object.area() * context.emitters.len() as f64),
+            _ => 0.0,
+        }
+    }
+}
+
+impl<'a> Context<'a> {
+    // The density, per unit solid angle, with which the camera sends rays along `direction`. Only
+    // needed when light tracing, so the camera is a pinhole.
+    fn camera_pdf(&self, direction: Vector3<f64>) -> f64 {
+        let camera = &self.scene.camera;
+        let (half_width, half_height) = camera.sensor_size(self.scene.width, self.scene.height);
+        let (_, _, forward) = camera.basis();
+        let cosine = direction.dot(forward);
+        if !self.light_tracing || cosine <= 0.0 {
+            return 0.0;
+        }
+        match camera.project(camera.position + direction) {
+            Some((x, y)) if x.abs() <= half_width && y.abs() <= half_height => {
+                1.0 / (4.0 * half_width * half_height * cosine * cosine * cosine)
+            }
+            _ => 0.0,
+        }
+    }
+
+    // The pixel position `point` is seen at by the camera
+    fn raster(&self, point: Point3<f64>) -> Option<Point2<f64>> {
+        let scene = self.scene;
+        let (half_width, half_height) = scene.camera.sensor_size(scene.width, scene.height);
+        let (x, y) = scene.camera.project(point)?;
+        let raster = Point2::new(
+            (x / half_width + 1.0) * 0.5 * scene.width as f64,
+            (1.0 - y / half_height) * 0.5 * scene.height as f64,
+        );
+        if raster.x < 0.0 || raster.y < 0.0 || raster.x >= scene.width as f64 || raster.y >= scene.height as f64 {
+            return None;
+        }
+        Some(raster)
+    }
+
+    // Can `to` be seen from `from`?
+    fn visible(&self, from: &Vertex, to: &Vertex) -> bool {
+        let bias = self.scene.shadow_bias;
+        let direction = (to.point - from.point).normalize();
+        let ray = Ray::create_bounce(from.normal, direction, from.point, bias);
+        !self.scene.occluded(&ray, ray.origin.distance(to.point) - 2.0 * bias)
+    }
+
+    // Extend the subpath with a random walk along `ray`, leaving `previous` with the given solid angle
+    // density, until it escapes, is absorbed or has `max_vertices` vertices
+    fn random_walk(
+        &self,
+        mut ray: Ray,
+        mut throughput: Color,
+        mut pdf: f64,
+        max_vertices: usize,
+        path: &mut Vec<Vertex<'a>>,
+        sampler: &mut dyn Sampler,
+    ) {
+        while path.len() < max_vertices {
+            let hit = match self.scene.trace(&ray) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let wo = -ray.direction;
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface,
+                point: hit.point,
+                normal: hit.normal,
+                wo,
+                bsdf: Some(Bsdf::new(&hit)),
+                object: Some(hit.object),
+                throughput,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+                delta: false,
+            };
+            let previous = path.len() - 1;
+            vertex.pdf_fwd = path[previous].to_area(pdf, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let lobe = sampler.get_1d();
+            let direction_sample = sampler.get_2d();
+            let current = path.len() - 1;
+            let bsdf = path[current].bsdf.as_ref().unwrap();
+            let sample = match bsdf.sample(wo, lobe, direction_sample) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput = throughput * sample.weight;
+            // Mirrors and glass can't be sampled the other way, so give them no density at all
+            let pdf_rev = if sample.specular { 0.0 } else { bsdf.pdf(sample.direction, wo) };
+            pdf = if sample.specular { 0.0 } else { sample.pdf };
+            path[current].delta = sample.specular;
+            path[previous].pdf_rev = path[current].to_area(pdf_rev, &path[previous]);
+            ray = Ray::create_bounce(hit.normal, sample.direction, hit.point, self.scene.shadow_bias);
+        }
+    }
+
+    fn camera_subpath(&self, ray: &Ray, sampler: &mut dyn Sampler) -> Vec<Vertex<'a>> {
+        let (_, _, forward) = self.scene.camera.basis();
+        let mut path = vec![Vertex {
+            kind: VertexKind::Camera,
+            point: ray.origin,
+            normal: forward,
+            wo: Vector3::new(0.0, 0.0, 0.0),
+            bsdf: None,
+            object: None,
+            throughput: Color::white(),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }];
+        let pdf = self.camera_pdf(ray.direction);
+        let ray = Ray { origin: ray.origin, direction: ray.direction };
+        self.random_walk(ray, Color::white(), pdf, self.max_depth + 2, &mut path, sampler);
+        path
+    }
+
+    fn light_subpath(&self, sampler: &mut dyn Sampler) -> Vec<Vertex<'a>> {
+        let light_choice = sampler.get_1d();
+        let position_sample = sampler.get_2d();
+        let direction_sample = sampler.get_2d();
+        let count = self.emitters.len();
+        if count == 0 {
+            return vec![];
+        }
+        let emitter = self.emitters.get(((light_choice * count as f64) as usize).min(count - 1));
+        let surface = match emitter.sample_surface(position_sample) {
+            Some(surface) => surface,
+            None => return vec![],
+        };
+        let emission = emitter.material().emission;
+        let origin = Vertex {
+            kind: VertexKind::Light,
+            point: surface.point,
+            normal: surface.normal,
+            wo: surface.normal,
+            bsdf: None,
+            object: Some(emitter),
+            throughput: emission,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        };
+        let pdf_origin = origin.pdf_light_origin(self);
+
+        // Emitters give off light in a cosine-weighted spread from their front
+        let direction = cosine_hemisphere(surface.normal, direction_sample);
+        let pdf_direction = direction.dot(surface.normal) / PI;
+        if pdf_direction <= 0.0 {
+            return vec![];
+        }
+        let throughput = emission * (direction.dot(surface.normal) / (pdf_origin * pdf_direction)) as f32;
+        let ray = Ray::create_bounce(surface.normal, direction, surface.point, self.scene.shadow_bias);
+        let mut path = vec![Vertex { pdf_fwd: pdf_origin, ..origin }];
+        self.random_walk(ray, throughput, pdf_direction, self.max_depth + 1, &mut path, sampler);
+        path
+    }
+
+    // Weight the path made by joining the first `s` light vertices to the first `t` camera vertices,
+    // against every other way of splitting the same path between the two subpaths. `sampled` stands
+    // in for the last light vertex when s is 1, or for the camera when t is 1, since those are picked
+    // afresh for the join.
+    fn mis_weight(&self, light_path: &[Vertex], camera_path: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light_path[s - 1]),
+        };
+        let pt = if t == 1 { sampled.unwrap() } else { &camera_path[t - 1] };
+        let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+        let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+
+        // Emitters that can't be sampled, such as infinite planes, can only be found by hitting them
+        if s == 0 && !pt.object.is_some_and(|object| object.area().is_finite()) {
+            return 1.0;
+        }
+
+        // (pdf_fwd, pdf_rev, delta) along each subpath, as they are once joined
+        let vertex_pdfs = |vertex: &Vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta);
+        let mut light: Vec<(f64, f64, bool)> = light_path[..s].iter().map(vertex_pdfs).collect();
+        let mut camera: Vec<(f64, f64, bool)> = camera_path[..t].iter().map(vertex_pdfs).collect();
+        if s == 1 {
+            light[0] = vertex_pdfs(sampled.unwrap());
+        }
+        if t == 1 {
+            camera[0] = vertex_pdfs(sampled.unwrap());
+        }
+        camera[t - 1].2 = false;
+        camera[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(self, qs_minus, pt),
+            None => pt.pdf_light_origin(self),
+        };
+        if let Some(pt_minus) = pt_minus {
+            camera[t - 2].1 = match qs {
+                Some(qs) => pt.pdf(self, Some(qs), pt_minus),
+                None => pt.pdf_light(pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            light[s - 1].2 = false;
+            light[s - 1].1 = pt.pdf(self, pt_minus, qs);
+        }
+        if let (Some(qs), Some(qs_minus)) = (qs, qs_minus) {
+            light[s - 2].1 = qs.pdf(self, Some(pt), qs_minus);
+        }
+
+        // Each other strategy's density relative to this one's, built up a vertex at a time
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera[i].1) / remap(camera[i].0);
+            if !camera[i].2 && !camera[i - 1].2 && (i > 1 || self.light_tracing) {
+                sum += ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light[i].1) / remap(light[i].0);
+            if !light[i].2 && (i == 0 || !light[i - 1].2) {
+                sum += ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+
+    // The light carried by the path joining the first `s` light vertices to the first `t` camera
+    // vertices, MIS weighted. Joins straight to the camera go to `splat` rather than being returned.
+    fn connect(
+        &self,
+        light_path: &[Vertex<'a>],
+        camera_path: &[Vertex<'a>],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+        splat: &mut dyn FnMut(Point2<f64>, Color),
+    ) -> Color {
+        if s == 0 {
+            // The camera subpath found an emitter by itself
+            let pt = &camera_path[t - 1];
+            if pt.kind != VertexKind::Surface || pt.normal.dot(pt.wo) <= 0.0 || pt.emission() == Color::black() {
+                return Color::black();
+            }
+            let light = pt.throughput * pt.emission();
+            return light * self.mis_weight(light_path, camera_path, None, s, t) as f32;
+        }
+
+        if t == 1 {
+            // Light tracing: join the light subpath straight to the camera, wherever on the image that lands
+            let qs = &light_path[s - 1];
+            if !self.light_tracing || qs.kind != VertexKind::Surface || !qs.is_connectible() {
+                return Color::black();
+            }
+            let raster = match self.raster(qs.point) {
+                Some(raster) => raster,
+                None => return Color::black(),
+            };
+            let camera = &self.scene.camera;
+            let (_, _, forward) = camera.basis();
+            let to_camera = camera.position - qs.point;
+            let distance2 = to_camera.magnitude2();
+            let wi = to_camera / distance2.sqrt();
+            let cosine = (-wi).dot(forward);
+            // The camera's importance, over the density of picking its one point as seen from qs
+            let importance = self.camera_pdf(-wi) / cosine * cosine / distance2;
+            let sampled = Vertex {
+                kind: VertexKind::Camera,
+                point: camera.position,
+                normal: forward,
+                wo: Vector3::new(0.0, 0.0, 0.0),
+                bsdf: None,
+                object: None,
+                throughput: Color::white() * importance as f32,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+                delta: false,
+            };
+            let light = qs.throughput * qs.f(&sampled) * sampled.throughput * wi.dot(qs.normal).abs() as f32;
+            if light == Color::black() || !self.visible(qs, &sampled) {
+                return Color::black();
+            }
+            let weight = self.mis_weight(light_path, camera_path, Some(&sampled), s, t);
+            splat(raster, light * weight as f32);
+            return Color::black();
+        }
+
+        let pt = &camera_path[t - 1];
+        if !pt.is_connectible() {
+            return Color::black();
+        }
+
+        if s == 1 {
+            // Pick a fresh point on an emitter to join to, as the path tracer samples lights
+            let light_choice = sampler.get_1d();
+            let position_sample = sampler.get_2d();
+            let count = self.emitters.len();
+            if count == 0 {
+                return Color::black();
+            }
+            let emitter = self.emitters.get(((light_choice * count as f64) as usize).min(count - 1));
+            let surface = match emitter.sample_surface(position_sample) {
+                Some(surface) => surface,
+                None => return Color::black(),
+            };
+            let to_light = surface.point - pt.point;
+            let distance2 = to_light.magnitude2();
+            let wi = to_light / distance2.sqrt();
+            let light_cosine = surface.normal.dot(-wi);
+            if light_cosine <= 0.0 {
+                return Color::black();
+            }
+            let pdf = distance2 / (light_cosine * emitter.area() * count as f64);
+            let mut sampled = Vertex {
+                kind: VertexKind::Light,
+                point: surface.point,
+                normal: surface.normal,
+                wo: surface.normal,
+                bsdf: None,
+                object: Some(emitter),
+                throughput: emitter.material().emission * (1.0 / pdf) as f32,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+                delta: false,
+            };
+            sampled.pdf_fwd = sampled.pdf_light_origin(self);
+            let light = pt.throughput * pt.f(&sampled) * sampled.throughput * wi.dot(pt.normal).abs() as f32;
+            if light == Color::black() || !self.visible(pt, &sampled) {
+                return Color::black();
+            }
+            return light * self.mis_weight(light_path, camera_path, Some(&sampled), s, t) as f32;
+        }
+
+        // Join two vertices in the middle of the path
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible() {
+            return Color::black();
+        }
+        let light = qs.throughput * qs.f(pt) * pt.f(qs) * pt.throughput;
+        if light == Color::black() || !self.visible(pt, qs) {
+            return Color::black();
+        }
+        let offset = qs.point - pt.point;
+        let distance2 = offset.magnitude2();
+        let direction = offset / distance2.sqrt();
+        let geometry = qs.normal.dot(direction).abs() * pt.normal.dot(direction).abs() / distance2;
+        light * (geometry * self.mis_weight(light_path, camera_path, None, s, t)) as f32
+    }
+
+    fn radiance(&self, ray: &Ray, sampler: &mut dyn Sampler, splat: &mut dyn FnMut(Point2<f64>, Color)) -> Color {
+        let camera_path = self.camera_subpath(ray, sampler);
+        let light_path = self.light_subpath(sampler);
+
+        let mut radiance = Color::black();
+        for t in 1..=camera_path.len() {
+            // Point, spot and directional lights, which no subpath can start from or hit
+            let pt = &camera_path[t - 1];
+            if t >= 2 && t - 1 <= self.max_depth && pt.is_connectible() {
+                let bsdf = pt.bsdf.as_ref().unwrap();
+                radiance = radiance + pt.throughput * delta_lighting(self.scene, pt.point, bsdf, pt.wo);
+            }
+
+            for s in 0..=light_path.len() {
+                // Depth counts the bounces between the light and the camera
+                if (s == 1 && t == 1) || s + t < 2 || s + t - 2 > self.max_depth {
+                    continue;
+                }
+                radiance = radiance + self.connect(&light_path, &camera_path, s, t, sampler, splat);
+            }
+        }
+        radiance
+    }
+}
+
+impl BidirectionalPathTracer {
+    fn context<'a>(&self, scene: &'a Scene, light_tracing: bool) -> Context<'a> {
+        Context {
+            scene,
+            emitters: scene.emitters(),
+            light_tracing: light_tracing && scene.camera.is_pinhole(),
+            max_depth: scene.max_recursion_depth as usize + 1,
+        }
+    }
+}
+
+impl Integrator for BidirectionalPathTracer {
+    // Without a film to splat onto, light tracing's share of each path is left to the other strategies
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        self.context(scene, false).radiance(ray, sampler, &mut |_, _| {})
+    }
+
+    fn radiance_and_splats(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        splat: &mut dyn FnMut(Point2<f64>, Color),
+    ) -> Color {
+        self.context(scene, true).radiance(ray, sampler, splat)
+    }
+}
+
+#[test]
+fn test_bidirectional_path_tracer() {
+    use crate::{estimate_radiance, lamp_scene, render_film, Sphere};
+    use crate::camera::Camera;
+    use crate::material::Material;
+    use crate::path_tracer::PathTracer;
+    use crate::sampler::SobolSampler;
+
+    // The lamp scene with a ball beside the lamp, seen from the front
+    let mut scene = lamp_scene(vec![
+        Box::new(Sphere {
+            center: Point3::new(1.5, 0.5, 0.0),
+            radius: 0.5,
+            material: Material::matte(Color {red: 0.5, green: 0.5, blue: 0.5}),
+        }),
+    ]);
+    scene.width = 8;
+    scene.height = 6;
+    scene.camera = Camera { position: Point3::new(0.0, 0.5, 3.0), look_at: Point3::new(0.0, 0.3, 0.0), ..Camera::default() };
+    scene.max_recursion_depth = 3;
+    scene.integrator = Box::new(BidirectionalPathTracer);
+
+    // Directly under the lamp the floor shows a quarter of the lamp's brightness (see lamp_scene),
+    // plus a little light bounced off the ball
+    let down = Ray { origin: Point3::new(0.0, 0.5, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+    let total = estimate_radiance(&scene, &BidirectionalPathTracer, &down, 2048);
+    assert!(total.red > 0.25 && total.red < 0.27, "{:?}", total);
+
+    // Rendering with light tracing splatted onto the film agrees with plain path tracing
+    let average = |scene: &Scene| {
+        let film = render_film(scene, None);
+        let mut sum = 0.0;
+        for y in 0..scene.height {
+            for x in 0..scene.width {
+                sum += film.get_color(x, y).red;
+            }
+        }
+        sum / (scene.width * scene.height) as f32
+    };
+    scene.sampler = Box::new(SobolSampler::new(256, 0));
+    let bidirectional = average(&scene);
+    scene.integrator = Box::new(PathTracer { roulette_depth: 8 });
+    let path_traced = average(&scene);
+    assert!((bidirectional - path_traced).abs() < 0.02 * path_traced, "{} vs {}", bidirectional, path_traced);
+}
@@ -0,0 +1,210 @@
+// How a surface scatters light, built from its material for Monte Carlo light transport. Directions
+// are unit vectors pointing away from the surface: `wo` towards where the light goes (the viewer) and
+// `wi` towards where it comes from.
+//
+// A material is a mix of up to three parts, picked between with the same weights the Whitted shading
+// in `shade` blends them with: glass (`1 - opacity`), a perfect mirror (`reflectivity` of the rest),
+// and a rough lobe of Lambertian diffuse plus a Phong highlight for whatever's left. Glass and mirrors
+// scatter into a single direction, so only the rough lobe can be evaluated for an arbitrary pair of
+// directions.
+
+use std::f64::consts::PI;
+
+use cgmath::{Point2, Vector3, InnerSpace};
+
+use crate::{fresnel, orthonormal_basis, reflect, refract, Color, Intersection};
+
+pub struct Bsdf {
+    normal: Vector3<f64>,   // unit surface normal, pointing out of the object
+    diffuse: Color,
+    specular: Color,
+    shininess: f64,
+    refractive_index: f64,
+    dielectric: f32,        // chance of reflecting or refracting like glass
+    mirror: f32,            // chance of a mirror reflection
+    rough: f32,             // weight of the rough lobe, the chance of sampling it
+    diffuse_chance: f64,    // within the rough lobe, the chance of sampling diffuse over the highlight
+}
+
+pub struct BsdfSample {
+    pub direction: Vector3<f64>,    // wi
+    pub weight: Color,              // the BSDF times the cosine over the pdf: the path's throughput
+    pub pdf: f64,                   // solid angle density of the direction; meaningless if specular
+    pub specular: bool,             // from glass or a mirror, which can't be hit by chance
+}
+
+impl Bsdf {
+    pub fn new(hit: &Intersection) -> Bsdf {
+        let material = hit.object.material();
+        let diffuse = material.color_at(hit.uv);
+        let transparency = 1.0 - material.opacity;
+        let opaque_weight = diffuse.luminance() + material.specular.luminance();
+        Bsdf {
+            normal: hit.normal,
+            diffuse,
+            specular: material.specular,
+            shininess: material.shininess as f64,
+            refractive_index: material.refractive_index,
+            dielectric: transparency,
+            mirror: material.opacity * material.reflectivity,
+            rough: material.opacity * (1.0 - material.reflectivity),
+            diffuse_chance: if opaque_weight > 0.0 { (diffuse.luminance() / opaque_weight) as f64 } else { 1.0 },
+        }
+    }
+
+    pub fn normal(&self) -> Vector3<f64> {
+        self.normal
+    }
+
+    // Can light arriving from one direction be scattered into an arbitrary other one?
+    pub fn is_rough(&self) -> bool {
+        self.rough > 0.0 && (self.diffuse != Color::black() || self.specular != Color::black())
+    }
+
+    // The normal on the side `wo` lies on, since surfaces are lit from whichever side they're seen
+    fn facing(&self, wo: Vector3<f64>) -> Vector3<f64> {
+        if wo.dot(self.normal) < 0.0 { -self.normal } else { self.normal }
+    }
+
+    // The mirror direction of `wo`, the center of the Phong highlight
+    fn highlight_axis(&self, wo: Vector3<f64>) -> Vector3<f64> {
+        reflect(-wo, self.facing(wo))
+    }
+
+    // How much light arriving from `wi` is scattered towards `wo`, per unit solid angle
+    pub fn evaluate(&self, wo: Vector3<f64>, wi: Vector3<f64>) -> Color {
+        let normal = self.facing(wo);
+        if self.rough == 0.0 || wi.dot(normal) <= 0.0 {
+            return Color::black();
+        }
+        // Normalised so the highlight never reflects more than Ks
+        // REF: Lafortune and Willems, "Using the Modified Phong Reflectance Model for Physically Based Rendering", 1994
+        let cos_alpha = wi.dot(self.highlight_axis(wo)).max(0.0);
+        let highlight = (self.shininess + 2.0) / (2.0 * PI) * cos_alpha.powf(self.shininess);
+        (self.diffuse * std::f32::consts::FRAC_1_PI + self.specular * highlight as f32) * self.rough
+    }
+
+    // The density with which `sample` picks `wi`, per unit solid angle
+    pub fn pdf(&self, wo: Vector3<f64>, wi: Vector3<f64>) -> f64 {
+        let normal = self.facing(wo);
+        let cos_theta = wi.dot(normal);
+        if self.rough == 0.0 || cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let cos_alpha = wi.dot(self.highlight_axis(wo)).max(0.0);
+        let diffuse_pdf = cos_theta / PI;
+        let highlight_pdf = (self.shininess + 1.0) / (2.0 * PI) * cos_alpha.powf(self.shininess);
+        self.rough as f64 * (self.diffuse_chance * diffuse_pdf + (1.0 - self.diffuse_chance) * highlight_pdf)
+    }
+
+    // Pick the direction light arrives from, given the direction it leaves in. `lobe` chooses which
+    // part of the material scatters it and `sample` the direction within that part.
+    pub fn sample(&self, wo: Vector3<f64>, lobe: f64, sample: Point2<f64>) -> Option<BsdfSample> {
+        let normal = self.facing(wo);
+        let mut lobe = lobe as f32;
+
+        if lobe < self.dielectric {
+            // Reuse the choice of lobe to split between reflection and refraction by the Fresnel equations
+            lobe /= self.dielectric;
+            let reflected = lobe < fresnel(-wo, self.normal, self.refractive_index) as f32;
+            let direction = if reflected { None } else { refract(-wo, self.normal, self.refractive_index) };
+            let direction = direction.unwrap_or_else(|| reflect(-wo, normal));
+            return Some(BsdfSample { direction, weight: Color::white(), pdf: 0.0, specular: true });
+        }
+        lobe -= self.dielectric;
+        if lobe < self.mirror {
+            return Some(BsdfSample { direction: reflect(-wo, normal), weight: Color::white(), pdf: 0.0, specular: true });
+        }
+        if !self.is_rough() {
+            return None;
+        }
+
+        let direction = if (sample.x as f32) < self.diffuse_chance as f32 {
+            let remapped = Point2::new(sample.x / self.diffuse_chance, sample.y);
+            cosine_hemisphere(normal, remapped)
+        } else {
+            let remapped = Point2::new((sample.x - self.diffuse_chance) / (1.0 - self.diffuse_chance), sample.y);
+            phong_lobe(self.highlight_axis(wo), self.shininess, remapped)
+        };
+        let pdf = self.pdf(wo, direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let cos_theta = direction.dot(normal).abs();
+        let weight = self.evaluate(wo, direction) * (cos_theta / pdf) as f32;
+        Some(BsdfSample { direction, weight, pdf, specular: false })
+    }
+}
+
+// A direction about `normal` with density proportional to its cosine with the normal
+pub fn cosine_hemisphere(normal: Vector3<f64>, sample: Point2<f64>) -> Vector3<f64> {
+    // Points uniform on the disk, projected up onto the hemisphere
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    let r = sample.x.min(1.0).sqrt();
+    let phi = 2.0 * PI * sample.y;
+    let z = (1.0 - r * r).max(0.0).sqrt();
+    (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * z).normalize()
+}
+
+// A direction about `axis` with density proportional to its cosine with the axis to the `exponent`
+fn phong_lobe(axis: Vector3<f64>, exponent: f64, sample: Point2<f64>) -> Vector3<f64> {
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    let cos_alpha = sample.x.min(1.0).powf(1.0 / (exponent + 1.0));
+    let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+    let phi = 2.0 * PI * sample.y;
+    (tangent * (sin_alpha * phi.cos()) + bitangent * (sin_alpha * phi.sin()) + axis * cos_alpha).normalize()
+}
+
+#[test]
+fn test_bsdf_sampling() {
+    use cgmath::Point3;
+    use crate::{Sphere, material::Material};
+    use crate::sampler::{Sampler, SobolSampler};
+
+    let mut plastic = Material::matte(Color {red: 0.5, green: 0.4, blue: 0.3});
+    plastic.specular = Color {red: 0.3, green: 0.3, blue: 0.3};
+    plastic.shininess = 20.0;
+    let sphere = Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 1.0, material: plastic };
+    let hit = Intersection {
+        t: 1.0,
+        point: Point3::new(0.0, 1.0, 0.0),
+        normal: Vector3::new(0.0, 1.0, 0.0),
+        uv: Point2::new(0.0, 0.0),
+        object: &sphere,
+    };
+    let bsdf = Bsdf::new(&hit);
+    let wo = Vector3::new(0.6, 0.8, 0.0);
+
+    // Averaging the sample weights estimates the fraction of light reflected, which can't exceed the
+    // albedos, and every sample's pdf and weight agree with evaluating the BSDF directly
+    let mut sampler = SobolSampler::new(4096, 3);
+    let mut total = Color::black();
+    for index in 0..4096 {
+        sampler.start_pixel_sample((0, 0), index);
+        // Highlight directions that fall below the surface reflect nothing
+        let sample = match bsdf.sample(wo, sampler.get_1d(), sampler.get_2d()) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        assert!(!sample.specular && sample.direction.y > 0.0);
+        assert!((sample.pdf - bsdf.pdf(wo, sample.direction)).abs() < 1e-9);
+        let expected = bsdf.evaluate(wo, sample.direction) * (sample.direction.y / sample.pdf) as f32;
+        assert!((expected.red - sample.weight.red).abs() < 1e-5);
+        total = total + sample.weight * (1.0 / 4096.0);
+    }
+    assert!(total.red > 0.6 && total.red < 0.8);
+    assert!(total.blue > 0.4 && total.blue < 0.6);
+    // Light from below the surface isn't reflected, and the BSDF is symmetric
+    assert_eq!(bsdf.evaluate(wo, Vector3::new(0.0, -1.0, 0.0)), Color::black());
+    let wi = Vector3::new(-0.28, 0.96, 0.0);
+    assert!((bsdf.evaluate(wo, wi).red - bsdf.evaluate(wi, wo).red).abs() < 1e-6);
+
+    // Glass only ever reflects or refracts
+    let glass = Sphere { material: Material::transparent(1.5), ..sphere };
+    let bsdf = Bsdf::new(&Intersection { object: &glass, ..hit });
+    assert!(!bsdf.is_rough());
+    let reflected = bsdf.sample(wo, 0.01, Point2::new(0.5, 0.5)).unwrap();
+    assert!(reflected.specular && (reflected.direction - Vector3::new(-0.6, 0.8, 0.0)).magnitude() < 1e-9);
+    let refracted = bsdf.sample(wo, 0.9, Point2::new(0.5, 0.5)).unwrap();
+    assert!(refracted.specular && refracted.direction.y < 0.0);
+}
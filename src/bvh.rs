@@ -0,0 +1,412 @@
+// Bounding volume hierarchy over axis-aligned bounding boxes
+// REF: Pharr, Jakob and Humphreys, "Physically Based Rendering", 3rd ed., section 4.3
+
+use cgmath::{Point3, Vector3};
+
+use crate::{Color, Intersectable, Intersection, Ray};
+
+// An axis-aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f64>,
+    pub max: Point3<f64>,
+}
+
+impl Aabb {
+    // A box containing nothing, which any union will replace
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    // A box containing everything, for unbounded shapes such as planes
+    pub fn infinite() -> Aabb {
+        Aabb {
+            min: Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            max: Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Point3<f64>>>(points: I) -> Aabb {
+        points.into_iter().fold(Aabb::empty(), |aabb, point| aabb.union(&Aabb { min: point, max: point }))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Point3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Point3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        (0..3).all(|axis| self.min[axis].is_finite() && self.max[axis].is_finite())
+    }
+
+    pub fn centroid(&self) -> Point3<f64> {
+        Point3::new(
+            (self.min.x + self.max.x) * 0.5,
+            (self.min.y + self.max.y) * 0.5,
+            (self.min.z + self.max.z) * 0.5,
+        )
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    // The axis along which the box is longest
+    fn longest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d.x > d.y && d.x > d.z { 0 } else if d.y > d.z { 1 } else { 2 }
+    }
+
+    // Slab test: does the ray pass through the box somewhere between its origin and t_max?
+    fn hit(&self, ray: &Ray, inv_direction: &Vector3<f64>, t_max: f64) -> bool {
+        let mut t0 = 0.0f64;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let mut near = (self.min[axis] - ray.origin[axis]) * inv_direction[axis];
+            let mut far = (self.max[axis] - ray.origin[axis]) * inv_direction[axis];
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // Widen the far distance slightly so rounding can't drop hits that graze the box
+            // (f64::max/min also skip the NaN from a ray lying exactly in a slab's plane)
+            t0 = t0.max(near);
+            t1 = t1.min(far * (1.0 + 4.0 * f64::EPSILON));
+            if t0 > t1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub trait Bounded {
+    fn bounding_box(&self) -> Aabb;
+}
+
+// A node in the flattened tree. Nodes are stored depth first, so an interior node's first child
+// always follows it directly and only the second child's position needs storing.
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    bounds: Aabb,
+    offset: u32,    // leaves: first entry in `indices`; interior nodes: position of the second child
+    count: u16,     // leaves: number of primitives; interior nodes: 0
+    axis: u8,       // interior nodes: the axis the children were split along
+}
+
+const MAX_LEAF_SIZE: usize = 4;
+// Deepest a traversal's stack of nodes still to visit can get. Below half of it, nodes split at the median
+// rather than by cost, which keeps the depth under it for any number of primitives.
+const MAX_DEPTH: usize = 64;
+const SAH_BUCKETS: usize = 12;
+// Cost of visiting a node relative to intersecting one primitive
+const TRAVERSAL_COST: f64 = 0.125;
+
+// The tree itself, which refers to primitives by their position in the caller's list
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,  // primitives with infinite bounds, which every ray has to test
+}
+
+impl Bvh {
+    // Build a tree over primitives with the given bounds, splitting by the surface-area heuristic
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let (mut indices, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..bounds.len()).partition(|&i| bounds[i].is_finite());
+        let centroids: Vec<Point3<f64>> = bounds.iter().map(Aabb::centroid).collect();
+
+        let mut nodes = Vec::with_capacity(2 * indices.len());
+        if !indices.is_empty() {
+            build(&mut nodes, bounds, &centroids, &mut indices, 0, 0);
+        }
+        Bvh { nodes, indices, unbounded }
+    }
+
+    // Offer the ray to every primitive whose bounds it passes through before t_max, nearer children first.
+    // `visit` gets the primitive's index and the current t_max, and returns the distance to any closer
+    // hit, which then prunes the rest of the search.
+    pub fn traverse<F: FnMut(usize, f64) -> Option<f64>>(&self, ray: &Ray, t_max: f64, mut visit: F) {
+        self.walk(ray, t_max, |index, t_max| {
+            if let Some(t) = visit(index, *t_max) {
+                *t_max = t_max.min(t);
+            }
+            false
+        });
+    }
+
+    // Is any primitive hit before t_max? Stops at the first primitive `test` reports as hit,
+    // which makes this cheaper than finding the closest hit.
+    pub fn any_hit<F: FnMut(usize, f64) -> bool>(&self, ray: &Ray, t_max: f64, mut test: F) -> bool {
+        self.walk(ray, t_max, |index, t_max| test(index, *t_max))
+    }
+
+    // Walk the tree, offering `visit` each candidate primitive and letting it shrink t_max.
+    // Returns true as soon as `visit` asks to stop.
+    fn walk<F: FnMut(usize, &mut f64) -> bool>(&self, ray: &Ray, mut t_max: f64, mut visit: F) -> bool {
+        for &index in &self.unbounded {
+            if visit(index, &mut t_max) {
+                return true;
+            }
+        }
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_direction = Vector3::new(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+        // A fixed stack, so rays don't allocate
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.bounds.hit(ray, &inv_direction, t_max) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for &index in &self.indices[start..start + node.count as usize] {
+                        if visit(index, &mut t_max) {
+                            return true;
+                        }
+                    }
+                } else if inv_direction[node.axis as usize] < 0.0 {
+                    // The ray runs towards negative values along the split axis, so the second child is nearer
+                    stack[stack_size] = current + 1;
+                    stack_size += 1;
+                    current = node.offset as usize;
+                    continue;
+                } else {
+                    stack[stack_size] = node.offset as usize;
+                    stack_size += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+            if stack_size == 0 {
+                return false;
+            }
+            stack_size -= 1;
+            current = stack[stack_size];
+        }
+    }
+}
+
+// Recursively build the subtree over `indices`, which start at `offset` in the final index list.
+// Returns the position of the subtree's root node.
+fn build(nodes: &mut Vec<Node>, bounds: &[Aabb], centroids: &[Point3<f64>], indices: &mut [usize], offset: usize, depth: usize) -> usize {
+    let node_bounds = indices.iter().fold(Aabb::empty(), |aabb, &i| aabb.union(&bounds[i]));
+    let position = nodes.len();
+    let leaf = Node { bounds: node_bounds, offset: offset as u32, count: indices.len() as u16, axis: 0 };
+    nodes.push(leaf);
+
+    let count = indices.len();
+    if count <= MAX_LEAF_SIZE {
+        return position;
+    }
+    let centroid_bounds = Aabb::from_points(indices.iter().map(|&i| centroids[i]));
+    let axis = centroid_bounds.longest_axis();
+    let (low, high) = (centroid_bounds.min[axis], centroid_bounds.max[axis]);
+
+    let mid = if depth >= MAX_DEPTH / 2 {
+        0
+    } else if high <= low {
+        // Every centroid is in the same place, so no split can separate them
+        if count <= u16::MAX as usize {
+            return position;
+        }
+        count / 2
+    } else {
+        // Bin the centroids along the axis and find the cheapest split between bins
+        let bucket = |i: usize| (((centroids[i][axis] - low) / (high - low) * SAH_BUCKETS as f64) as usize).min(SAH_BUCKETS - 1);
+        let mut bucket_counts = [0usize; SAH_BUCKETS];
+        let mut bucket_bounds = [Aabb::empty(); SAH_BUCKETS];
+        for &i in indices.iter() {
+            let b = bucket(i);
+            bucket_counts[b] += 1;
+            bucket_bounds[b] = bucket_bounds[b].union(&bounds[i]);
+        }
+
+        let mut best_split = 0;
+        let mut best_cost = f64::INFINITY;
+        for split in 0..SAH_BUCKETS - 1 {
+            let (mut below, mut above) = (Aabb::empty(), Aabb::empty());
+            let (mut below_count, mut above_count) = (0, 0);
+            for b in 0..=split {
+                below = below.union(&bucket_bounds[b]);
+                below_count += bucket_counts[b];
+            }
+            for b in split + 1..SAH_BUCKETS {
+                above = above.union(&bucket_bounds[b]);
+                above_count += bucket_counts[b];
+            }
+            let cost = TRAVERSAL_COST
+                + (below_count as f64 * below.surface_area() + above_count as f64 * above.surface_area())
+                    / node_bounds.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        // Splitting isn't worth it if a leaf would be cheaper, as long as the leaf stays small
+        if best_cost >= count as f64 && count <= MAX_LEAF_SIZE * 4 {
+            return position;
+        }
+        partition(indices, |i| bucket(i) <= best_split)
+    };
+
+    // Fall back to splitting at the median centroid if the buckets put everything on one side, or the tree
+    // is getting too deep
+    let mid = if mid == 0 || mid == count {
+        indices.select_nth_unstable_by(count / 2, |&a, &b| centroids[a][axis].total_cmp(&centroids[b][axis]));
+        count / 2
+    } else {
+        mid
+    };
+
+    let (below, above) = indices.split_at_mut(mid);
+    build(nodes, bounds, centroids, below, offset, depth + 1);
+    let second = build(nodes, bounds, centroids, above, offset + mid, depth + 1);
+    nodes[position] = Node { bounds: node_bounds, offset: second as u32, count: 0, axis: axis as u8 };
+    position
+}
+
+// Move the entries matching `predicate` to the front, returning how many there are
+fn partition<F: Fn(usize) -> bool>(indices: &mut [usize], predicate: F) -> usize {
+    let mut mid = 0;
+    for i in 0..indices.len() {
+        if predicate(indices[i]) {
+            indices.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+// A collection of objects with a BVH over them
+pub struct Aggregate<T: ?Sized> {
+    pub objects: Vec<Box<T>>,
+    emitters: Vec<usize>,   // the objects that give off light and can be sampled as area lights
+    bvh: Bvh,
+}
+
+impl<T: Intersectable + ?Sized> Aggregate<T> {
+    pub fn new(objects: Vec<Box<T>>) -> Aggregate<T> {
+        let bounds: Vec<Aabb> = objects.iter().map(|object| object.bounding_box()).collect();
+        let emitters = (0..objects.len())
+            .filter(|&i| objects[i].material().emission != Color::black() && objects[i].area().is_finite())
+            .collect();
+        Aggregate { bvh: Bvh::new(&bounds), emitters, objects }
+    }
+
+    // Positions in `objects` of the objects that give off light and can be sampled as area lights
+    pub fn emitter_indices(&self) -> &[usize] {
+        &self.emitters
+    }
+
+    // Find the closest intersection of the ray with any of the objects
+    pub fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let mut nearest = None;
+        self.bvh.traverse(ray, f64::INFINITY, |i, t_max| {
+            let hit = self.objects[i].intersect(ray).filter(|hit| hit.t < t_max)?;
+            let t = hit.t;
+            nearest = Some(hit);
+            Some(t)
+        });
+        nearest
+    }
+
+    // Does any object block the ray before max_distance?
+    pub fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.bvh.any_hit(ray, max_distance, |i, t_max| self.objects[i].occludes(ray, t_max))
+    }
+}
+
+impl<T: Intersectable + ?Sized> Bounded for Aggregate<T> {
+    fn bounding_box(&self) -> Aabb {
+        self.objects.iter().fold(Aabb::empty(), |aabb, object| aabb.union(&object.bounding_box()))
+    }
+}
+
+#[test]
+fn test_bvh_matches_brute_force() {
+    use cgmath::InnerSpace;
+    use crate::{Color, Plane, Sphere};
+    use crate::material::Material;
+
+    // A deterministic scatter of spheres
+    let mut seed = 12345u64;
+    let mut random = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let mut objects: Vec<Box<dyn Intersectable>> = Vec::new();
+    for _ in 0..200 {
+        objects.push(Box::new(Sphere {
+            center: Point3::new(random() * 20.0 - 10.0, random() * 20.0 - 10.0, random() * -20.0 - 2.0),
+            radius: random() * 0.5 + 0.1,
+            material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+        }));
+    }
+    // Infinite planes can't go in the tree, but must still be found
+    objects.push(Box::new(Plane {
+        point: Point3::new(0.0, 0.0, -30.0),
+        normal: Vector3::new(0.0, 0.0, 1.0),
+        one_sided: false,
+        material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+    }));
+    let aggregate = Aggregate::new(objects);
+
+    for _ in 0..500 {
+        let ray = Ray {
+            origin: Point3::new(0.0, 0.0, 0.0),
+            direction: Vector3::new(random() * 2.0 - 1.0, random() * 2.0 - 1.0, -1.0).normalize(),
+        };
+        let expected = aggregate.objects.iter()
+            .filter_map(|object| object.intersect(&ray))
+            .map(|hit| hit.t)
+            .fold(f64::INFINITY, f64::min);
+        let actual = aggregate.intersect(&ray).map_or(f64::INFINITY, |hit| hit.t);
+        assert_eq!(expected, actual);
+        assert_eq!(expected < 25.0, aggregate.occluded(&ray, 25.0));
+    }
+}
+
+#[test]
+fn test_bvh_depth() {
+    use crate::{Color, Sphere};
+    use crate::material::Material;
+
+    // Spheres bunching up geometrically towards the origin split off one at a time by cost, which would
+    // make a tree as deep as there are spheres
+    let objects: Vec<Box<dyn Intersectable>> = (0..400)
+        .map(|i| {
+            let x = 0.5f64.powi(i);
+            Box::new(Sphere {
+                center: Point3::new(x, 0.0, -5.0),
+                radius: x * 0.01,
+                material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+            }) as Box<dyn Intersectable>
+        })
+        .collect();
+    let aggregate = Aggregate::new(objects);
+    fn depth(nodes: &[Node], current: usize) -> usize {
+        let node = &nodes[current];
+        if node.count > 0 {
+            return 1;
+        }
+        1 + depth(nodes, current + 1).max(depth(nodes, node.offset as usize))
+    }
+    assert!(depth(&aggregate.bvh.nodes, 0) <= MAX_DEPTH);
+
+    // A ray along the row passes through every sphere's bounds
+    let ray = Ray { origin: Point3::new(2.0, 0.0, -5.0), direction: Vector3::new(-1.0, 0.0, 0.0) };
+    assert!((aggregate.intersect(&ray).unwrap().t - 0.99).abs() < 1e-9);
+}
@@ -0,0 +1,402 @@
+use std::f64::consts::PI;
+
+use cgmath::{Point2, Point3, Vector3, InnerSpace};
+
+use crate::{orthonormal_basis, Ray};
+
+// Which way across the image the camera's field of view is measured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FovAxis {
+    Horizontal,
+    Vertical,
+    Diagonal,
+}
+
+// How the camera maps the image onto rays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    // Rays fan out from the camera's position to cover its field of view
+    Perspective,
+    // Rays run parallel to each other from an image plane `view_width` world units wide
+    Orthographic { view_width: f64 },
+    // Latitude/longitude panorama: the image covers every direction, 360 degrees across and 180 degrees down
+    Equirectangular,
+    // Equidistant fisheye: a pixel's distance from the image centre is proportional to its angle from
+    // the view direction, up to half the field of view. Pixels beyond that get no ray.
+    Fisheye,
+}
+
+// Which eye a stereo ray is for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+// How the two eyes' images are delivered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoLayout {
+    SideBySide,     // left eye on the left half of one image, right eye on the right
+    TopBottom,      // left eye on the top half of one image, right eye on the bottom
+    Separate,       // one image per eye
+}
+
+// Stereoscopic settings. The eyes sit `eye_separation` apart, either side of the camera's position, and
+// their views converge `convergence_distance` away: objects there appear at the depth of the screen.
+// Panoramas use omni-directional stereo, where the eyes circle the camera's position as the view turns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stereo {
+    pub eye_separation: f64,
+    pub convergence_distance: f64,
+    pub layout: StereoLayout,
+}
+
+// A camera at `position`, looking towards `look_at` with `up` roughly upwards in the image
+pub struct Camera {
+    pub position: Point3<f64>,
+    pub look_at: Point3<f64>,
+    pub up: Vector3<f64>,
+    pub fov: f64,           // field of view in degrees
+    pub fov_axis: FovAxis,  // the image axis `fov` spans
+    pub projection: Projection,
+    pub aperture: f64,          // radius of the lens; 0.0 is a pinhole with everything in focus. Panoramic projections ignore it
+    pub focus_distance: f64,    // distance along the view direction to the plane in perfect focus
+    pub blades: u32,            // aperture blades, which give polygonal bokeh; 0 for a round aperture
+    pub stereo: Option<Stereo>, // None for a single, central view
+}
+
+impl Default for Camera {
+    // At the origin looking down -z, where prime rays used to start
+    fn default() -> Camera {
+        Camera {
+            position: Point3::new(0.0, 0.0, 0.0),
+            look_at: Point3::new(0.0, 0.0, -1.0),
+            up: Vector3::new(0.0, 1.0, 0.0),
+            fov: 90.0,
+            fov_axis: FovAxis::Vertical,
+            projection: Projection::Perspective,
+            aperture: 0.0,
+            focus_distance: 1.0,
+            blades: 0,
+            stereo: None,
+        }
+    }
+}
+
+impl Camera {
+    // Orthonormal basis of camera space: (right, up, forward) in world space
+    pub fn basis(&self) -> (Vector3<f64>, Vector3<f64>, Vector3<f64>) {
+        let forward = (self.look_at - self.position).normalize();
+        let right = forward.cross(self.up);
+        // An up vector along the view direction says nothing about which way is right, so any direction
+        // across the view will do
+        let right = if right.magnitude2() > 1e-12 { right.normalize() } else { orthonormal_basis(forward).0 };
+        let up = right.cross(forward);
+        (right, up, forward)
+    }
+
+    // Half the width and height of the sensor for an image of the given size. Perspective sensors sit one
+    // unit in front of the camera; orthographic ones are the image plane itself, in world units; panoramic
+    // ones are measured in radians.
+    pub fn sensor_size(&self, width: u32, height: u32) -> (f64, f64) {
+        let aspect_ratio = width as f64 / height as f64;
+        // Share out the extent along the field of view's axis between the image's width and height
+        let along_fov_axis = |half: f64| match self.fov_axis {
+            FovAxis::Horizontal => (half, half / aspect_ratio),
+            FovAxis::Vertical => (half * aspect_ratio, half),
+            FovAxis::Diagonal => {
+                let diagonal = (aspect_ratio * aspect_ratio + 1.0).sqrt();
+                (half * aspect_ratio / diagonal, half / diagonal)
+            }
+        };
+        match self.projection {
+            Projection::Perspective => along_fov_axis((self.fov.to_radians() / 2.0).tan()),
+            Projection::Orthographic { view_width } => (view_width / 2.0, view_width / 2.0 / aspect_ratio),
+            Projection::Equirectangular => (PI, PI / 2.0),
+            Projection::Fisheye => along_fov_axis(self.fov.to_radians() / 2.0),
+        }
+    }
+
+    // Does every ray leave from the one point? Only then can light traced from the scene towards the
+    // camera be projected back onto the image.
+    pub fn is_pinhole(&self) -> bool {
+        self.projection == Projection::Perspective && self.aperture <= 0.0 && self.stereo.is_none()
+    }
+
+    // Where a point in the scene lands on the sensor of a pinhole camera: the inverse of create_ray.
+    // None for points level with or behind the camera.
+    pub fn project(&self, point: Point3<f64>) -> Option<(f64, f64)> {
+        let (right, up, forward) = self.basis();
+        let offset = point - self.position;
+        let depth = offset.dot(forward);
+        if depth <= 0.0 {
+            return None;
+        }
+        Some((offset.dot(right) / depth, offset.dot(up) / depth))
+    }
+
+    // The world space ray for the point (sensor_x, sensor_y) on the sensor, or None if the projection
+    // doesn't cover that point. With an open aperture the ray starts from the point on the lens picked by
+    // `lens_sample`, which is uniform over (0..1, 0..1). Stereo cameras need the `eye` to look from.
+    pub fn create_ray(&self, sensor_x: f64, sensor_y: f64, lens_sample: Point2<f64>, eye: Option<Eye>) -> Option<Ray> {
+        let ray = self.central_ray(sensor_x, sensor_y)?;
+        let ray = match (self.stereo, eye) {
+            (Some(stereo), Some(eye)) => self.eye_ray(ray, &stereo, eye),
+            _ => ray,
+        };
+        if self.aperture <= 0.0 || !matches!(self.projection, Projection::Perspective | Projection::Orthographic { .. }) {
+            return Some(ray);
+        }
+
+        // Thin lens: every ray through this sensor point converges where the pinhole ray meets the focal plane
+        let (right, up, forward) = self.basis();
+        let focal_point = ray.origin + ray.direction * (self.focus_distance / ray.direction.dot(forward));
+        let lens = sample_aperture(lens_sample, self.blades);
+        let origin = ray.origin + (right * lens.x + up * lens.y) * self.aperture;
+        Some(Ray {
+            origin,
+            direction: (focal_point - origin).normalize(),
+        })
+    }
+
+    // Move a ray from the camera's position to one eye, re-aiming it so both eyes' rays for the same
+    // pixel meet at the convergence distance
+    fn eye_ray(&self, ray: Ray, stereo: &Stereo, eye: Eye) -> Ray {
+        let (right, _, forward) = self.basis();
+        let side = match eye {
+            Eye::Left => -0.5,
+            Eye::Right => 0.5,
+        };
+        let (offset, target) = match self.projection {
+            // Off-axis stereo: the eyes shift sideways and their views meet on the plane of convergence
+            Projection::Perspective => (right, ray.origin + ray.direction * (stereo.convergence_distance / ray.direction.dot(forward))),
+            Projection::Orthographic { .. } => (right, ray.origin + ray.direction * stereo.convergence_distance),
+            Projection::Fisheye => (right, ray.origin + ray.direction * stereo.convergence_distance),
+            // Omni-directional stereo: the eyes sit either side of the horizontal view direction
+            Projection::Equirectangular => {
+                let longitude = ray.direction.dot(right).atan2(ray.direction.dot(forward));
+                (right * longitude.cos() - forward * longitude.sin(), ray.origin + ray.direction * stereo.convergence_distance)
+            }
+        };
+        let origin = ray.origin + offset * (side * stereo.eye_separation);
+        Ray {
+            origin,
+            direction: (target - origin).normalize(),
+        }
+    }
+
+    // The ray through the sensor point from the camera's own position, before any stereo or lens effects
+    fn central_ray(&self, sensor_x: f64, sensor_y: f64) -> Option<Ray> {
+        let (right, up, forward) = self.basis();
+        let ray = match self.projection {
+            Projection::Perspective => Ray {
+                origin: self.position,
+                direction: (right * sensor_x + up * sensor_y + forward).normalize(),
+            },
+            Projection::Orthographic { .. } => Ray {
+                origin: self.position + right * sensor_x + up * sensor_y,
+                direction: forward,
+            },
+            Projection::Equirectangular => {
+                // The sensor position is the longitude and latitude of the ray
+                let (longitude, latitude) = (sensor_x, sensor_y);
+                Ray {
+                    origin: self.position,
+                    direction: right * (latitude.cos() * longitude.sin())
+                        + up * latitude.sin()
+                        + forward * (latitude.cos() * longitude.cos()),
+                }
+            }
+            Projection::Fisheye => {
+                // The distance from the centre of the sensor is the angle from the view direction
+                let theta = sensor_x.hypot(sensor_y);
+                if theta > self.fov.to_radians() / 2.0 {
+                    return None;
+                }
+                let scale = if theta > 0.0 { theta.sin() / theta } else { 1.0 };
+                Ray {
+                    origin: self.position,
+                    direction: right * (sensor_x * scale) + up * (sensor_y * scale) + forward * theta.cos(),
+                }
+            }
+        };
+        Some(ray)
+    }
+}
+
+// Map a uniform sample on the unit square to a uniform point on the unit disk, or on a regular
+// polygon with `blades` corners on the unit circle
+fn sample_aperture(sample: Point2<f64>, blades: u32) -> Point2<f64> {
+    if blades < 3 {
+        // Concentric mapping, which keeps neighbouring samples together
+        // REF: Shirley and Chiu, "A Low Distortion Map Between Disk and Square", 1997
+        let (a, b) = (2.0 * sample.x - 1.0, 2.0 * sample.y - 1.0);
+        if a == 0.0 && b == 0.0 {
+            return Point2::new(0.0, 0.0);
+        }
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, PI / 4.0 * (b / a))
+        } else {
+            (b, PI / 2.0 - PI / 4.0 * (a / b))
+        };
+        return Point2::new(r * theta.cos(), r * theta.sin());
+    }
+
+    // Pick one of the polygon's triangular wedges, then a uniform point inside it
+    let wedge = blades as f64 * sample.x;
+    let index = wedge.floor().min(blades as f64 - 1.0);
+    let u = wedge - index;
+    let corner = |i: f64| {
+        let angle = 2.0 * PI * i / blades as f64;
+        Vector3::new(angle.cos(), angle.sin(), 0.0)
+    };
+    let s = u.sqrt();
+    let point = corner(index) * (s * (1.0 - sample.y)) + corner(index + 1.0) * (s * sample.y);
+    Point2::new(point.x, point.y)
+}
+
+#[test]
+fn test_camera_basis() {
+    let camera = Camera {
+        position: Point3::new(1.0, 2.0, 3.0),
+        look_at: Point3::new(5.0, 2.0, 3.0),
+        up: Vector3::new(0.0, 1.0, 0.0),
+        fov: 90.0,
+        fov_axis: FovAxis::Vertical,
+        projection: Projection::Perspective,
+        aperture: 0.0,
+        focus_distance: 1.0,
+        blades: 0,
+        stereo: None,
+    };
+    let (right, up, forward) = camera.basis();
+    assert!((forward - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-9);
+    assert!((up - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+    // Looking down +x with +y up, +z is on the right
+    assert!((right - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+
+    let ray = camera.create_ray(0.0, 0.0, Point2::new(0.5, 0.5), None).unwrap();
+    assert_eq!(ray.origin, camera.position);
+    assert!((ray.direction - forward).magnitude() < 1e-9);
+    assert!(camera.create_ray(0.5, 0.0, Point2::new(0.5, 0.5), None).unwrap().direction.z > 0.0);
+
+    // Looking straight down with the usual up still gives a proper basis
+    let camera = Camera { position: Point3::new(0.0, 5.0, 0.0), look_at: Point3::new(0.0, 0.0, 0.0), ..Camera::default() };
+    let (right, up, forward) = camera.basis();
+    assert!((forward - Vector3::new(0.0, -1.0, 0.0)).magnitude() < 1e-9);
+    assert!((right.magnitude() - 1.0).abs() < 1e-9 && (up.magnitude() - 1.0).abs() < 1e-9);
+    assert!(right.dot(forward).abs() < 1e-9 && up.dot(forward).abs() < 1e-9 && right.dot(up).abs() < 1e-9);
+    let ray = camera.create_ray(0.2, 0.3, Point2::new(0.5, 0.5), None).unwrap();
+    assert!(ray.direction.x.is_finite() && ray.direction.y < 0.0);
+}
+
+#[test]
+fn test_sensor_size() {
+    fn assert_size(actual: (f64, f64), expected: (f64, f64)) {
+        assert!((actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+    }
+
+    let mut camera = Camera { fov: 90.0, ..Camera::default() };
+    // A 90 degree field of view spans one unit either side of the middle of the sensor
+    assert_size(camera.sensor_size(1600, 900), (16.0 / 9.0, 1.0));
+    assert_size(camera.sensor_size(900, 1600), (9.0 / 16.0, 1.0));
+    camera.fov_axis = FovAxis::Horizontal;
+    assert_size(camera.sensor_size(900, 1600), (1.0, 16.0 / 9.0));
+    camera.fov_axis = FovAxis::Diagonal;
+    assert_size(camera.sensor_size(400, 300), (0.8, 0.6));
+}
+
+#[test]
+fn test_orthographic_projection() {
+    let camera = Camera { projection: Projection::Orthographic { view_width: 4.0 }, ..Camera::default() };
+    assert_eq!(camera.sensor_size(200, 100), (2.0, 1.0));
+
+    // Every ray points straight ahead, starting from its own spot on the image plane
+    let left = camera.create_ray(-2.0, 0.0, Point2::new(0.5, 0.5), None).unwrap();
+    let right = camera.create_ray(2.0, 1.0, Point2::new(0.5, 0.5), None).unwrap();
+    assert_eq!(left.direction, right.direction);
+    assert_eq!(left.direction, Vector3::new(0.0, 0.0, -1.0));
+    assert_eq!(left.origin, Point3::new(-2.0, 0.0, 0.0));
+    assert_eq!(right.origin, Point3::new(2.0, 1.0, 0.0));
+}
+
+#[test]
+fn test_depth_of_field() {
+    for &blades in &[0, 6] {
+        let camera = Camera { aperture: 0.5, focus_distance: 4.0, blades, ..Camera::default() };
+        // Rays through the same sensor point leave from different places on the lens...
+        let a = camera.create_ray(0.3, -0.2, Point2::new(0.1, 0.8), None).unwrap();
+        let b = camera.create_ray(0.3, -0.2, Point2::new(0.9, 0.3), None).unwrap();
+        assert!((a.origin - b.origin).magnitude() > 0.1);
+        assert!(a.origin.x.hypot(a.origin.y) <= 0.5 && b.origin.x.hypot(b.origin.y) <= 0.5);
+        // ...and meet again on the plane of focus
+        let focus_a = a.origin + a.direction * (-4.0 / a.direction.z);
+        let focus_b = b.origin + b.direction * (-4.0 / b.direction.z);
+        assert!((focus_a - focus_b).magnitude() < 1e-9);
+        assert!((focus_a - Point3::new(1.2, -0.8, -4.0)).magnitude() < 1e-9);
+    }
+}
+
+#[test]
+fn test_panoramic_projections() {
+    let camera = Camera { projection: Projection::Equirectangular, ..Camera::default() };
+    assert_eq!(camera.sensor_size(400, 200), (PI, PI / 2.0));
+    let centre = Point2::new(0.5, 0.5);
+    // The middle of the panorama looks ahead, its left and right edges look behind, and its top looks up
+    assert!((camera.create_ray(0.0, 0.0, centre, None).unwrap().direction - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 1e-9);
+    assert!((camera.create_ray(PI, 0.0, centre, None).unwrap().direction - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    assert!((camera.create_ray(PI / 2.0, 0.0, centre, None).unwrap().direction - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-9);
+    assert!((camera.create_ray(0.0, PI / 2.0, centre, None).unwrap().direction - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+
+    let camera = Camera { projection: Projection::Fisheye, fov: 180.0, ..Camera::default() };
+    assert_eq!(camera.sensor_size(200, 200), (PI / 2.0, PI / 2.0));
+    // Angles from the view direction grow linearly across the image, out to the edge of the image circle
+    let ray = camera.create_ray(PI / 4.0, 0.0, centre, None).unwrap();
+    assert!((ray.direction - Vector3::new(1.0, 0.0, -1.0).normalize()).magnitude() < 1e-9);
+    let ray = camera.create_ray(0.0, -PI / 2.0, centre, None).unwrap();
+    assert!((ray.direction - Vector3::new(0.0, -1.0, 0.0)).magnitude() < 1e-9);
+    assert!(camera.create_ray(PI / 2.0, PI / 2.0, centre, None).is_none());
+}
+
+#[test]
+fn test_stereo() {
+    let stereo = Stereo { eye_separation: 0.064, convergence_distance: 2.0, layout: StereoLayout::SideBySide };
+    let camera = Camera { stereo: Some(stereo), ..Camera::default() };
+    let centre = Point2::new(0.5, 0.5);
+
+    // The eyes sit either side of the camera, and their views of a pixel meet on the plane of convergence
+    for &(x, y) in &[(0.0, 0.0), (0.4, -0.3)] {
+        let left = camera.create_ray(x, y, centre, Some(Eye::Left)).unwrap();
+        let right = camera.create_ray(x, y, centre, Some(Eye::Right)).unwrap();
+        assert_eq!(left.origin, Point3::new(-0.032, 0.0, 0.0));
+        assert_eq!(right.origin, Point3::new(0.032, 0.0, 0.0));
+        let meet_left = left.origin + left.direction * (-2.0 / left.direction.z);
+        let meet_right = right.origin + right.direction * (-2.0 / right.direction.z);
+        assert!((meet_left - meet_right).magnitude() < 1e-9);
+        assert!((meet_left - Point3::new(2.0 * x, 2.0 * y, -2.0)).magnitude() < 1e-9);
+    }
+
+    // In a stereo panorama, looking right (+x) puts the left eye ahead of the camera (-z)
+    let camera = Camera { projection: Projection::Equirectangular, ..camera };
+    let left = camera.create_ray(PI / 2.0, 0.0, centre, Some(Eye::Left)).unwrap();
+    assert!((left.origin - Point3::new(0.0, 0.0, -0.032)).magnitude() < 1e-9);
+    assert!(left.direction.x > 0.99);
+}
+
+#[test]
+fn test_project() {
+    let camera = Camera {
+        position: Point3::new(1.0, 2.0, 3.0),
+        look_at: Point3::new(0.0, 0.0, 0.0),
+        fov: 60.0,
+        ..Camera::default()
+    };
+    assert!(camera.is_pinhole());
+    assert!(!Camera { aperture: 0.1, ..camera }.is_pinhole());
+
+    // Projecting any point along a camera ray gives back the sensor position the ray came from
+    let ray = camera.create_ray(0.3, -0.2, Point2::new(0.5, 0.5), None).unwrap();
+    let (x, y) = camera.project(ray.origin + ray.direction * 7.0).unwrap();
+    assert!((x - 0.3).abs() < 1e-9 && (y + 0.2).abs() < 1e-9);
+    assert!(camera.project(ray.origin - ray.direction).is_none());
+}
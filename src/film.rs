@@ -0,0 +1,225 @@
+use cgmath::Point2;
+use image::{DynamicImage, GenericImage};
+
+use crate::Color;
+use crate::filter::Filter;
+
+// Running totals for one pixel: the filter-weighted sum of its samples' colors, and of the weights
+#[derive(Clone, Copy)]
+struct FilmPixel {
+    color: Color,
+    weight: f32,
+}
+
+// Filters with negative lobes can leave a pixel's weights summing to almost nothing, or less, and dividing
+// by that would blow the color up or flip its sign. Pixels weighted less than this are left empty.
+const MIN_WEIGHT: f32 = 1e-3;
+
+// Running mean and variance of the brightness of one pixel's own samples, updated one sample at a time
+// REF: Welford, "Note on a Method for Calculating Corrected Sums of Squares and Products", 1962
+#[derive(Clone, Copy, Default)]
+pub struct PixelVariance {
+    count: u32,
+    mean: f64,
+    m2: f64,    // sum of squared differences from the mean
+}
+
+impl PixelVariance {
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    // The unbiased estimate of the samples' variance
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 { 0.0 } else { self.m2 / (self.count - 1) as f64 }
+    }
+
+    // The standard error of the mean relative to its brightness, so dark pixels need as few samples as
+    // bright ones for the same visible noise. Very dark pixels are measured against a floor of 0.01
+    // rather than against almost nothing.
+    pub fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        (self.variance() / self.count as f64).sqrt() / self.mean.max(0.01)
+    }
+}
+
+// A floating point image that collects samples, spread over nearby pixels by a reconstruction filter,
+// until it's turned into a displayable image. Light tracing can also splat light straight onto any
+// pixel; those splats are averaged over every sample the whole image took, not just that pixel's.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<FilmPixel>,
+    variances: Vec<PixelVariance>,
+    splats: Vec<Color>,
+    sample_count: u64,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        let empty = FilmPixel { color: Color::black(), weight: 0.0 };
+        let size = (width * height) as usize;
+        Film {
+            width,
+            height,
+            pixels: vec![empty; size],
+            variances: vec![PixelVariance::default(); size],
+            splats: vec![Color::black(); size],
+            sample_count: 0,
+        }
+    }
+
+    // Add a sample taken at (x, y) in pixel coordinates, so (0.5, 0.5) is the center of the top left
+    // pixel, to every pixel within the filter's reach. The pixel it lies in also tracks its variance.
+    pub fn add_sample(&mut self, x: f64, y: f64, color: Color, filter: &dyn Filter) {
+        self.sample_count += 1;
+        if x >= 0.0 && y >= 0.0 && x < self.width as f64 && y < self.height as f64 {
+            self.variances[(y as u32 * self.width + x as u32) as usize].add(color.luminance() as f64);
+        }
+
+        let radius = filter.radius();
+        // Pixel centers sit at half-integer coordinates
+        let x0 = (x - 0.5 - radius).ceil().max(0.0) as u32;
+        let x1 = (x - 0.5 + radius).floor().min(self.width as f64 - 1.0);
+        let y0 = (y - 0.5 - radius).ceil().max(0.0) as u32;
+        let y1 = (y - 0.5 + radius).floor().min(self.height as f64 - 1.0);
+        if x1 < 0.0 || y1 < 0.0 {
+            return;
+        }
+
+        for py in y0..=y1 as u32 {
+            for px in x0..=x1 as u32 {
+                let weight = filter.evaluate(px as f64 + 0.5 - x, py as f64 + 0.5 - y) as f32;
+                if weight == 0.0 {
+                    continue;
+                }
+                let pixel = &mut self.pixels[(py * self.width + px) as usize];
+                pixel.color = pixel.color + color * weight;
+                pixel.weight += weight;
+            }
+        }
+    }
+
+    // Add light to the pixel under `position`, in pixel coordinates, unfiltered
+    pub fn add_splat(&mut self, position: Point2<f64>, color: Color) {
+        if position.x < 0.0 || position.y < 0.0 || position.x >= self.width as f64 || position.y >= self.height as f64 {
+            return;
+        }
+        let splat = &mut self.splats[(position.y as u32 * self.width + position.x as u32) as usize];
+        *splat = *splat + color;
+    }
+
+    // Statistics of the samples that landed in the pixel
+    pub fn variance(&self, x: u32, y: u32) -> &PixelVariance {
+        &self.variances[(y * self.width + x) as usize]
+    }
+
+    // The pixel's filtered color, plus the light splatted onto it
+    pub fn get_color(&self, x: u32, y: u32) -> Color {
+        let index = (y * self.width + x) as usize;
+        let pixel = &self.pixels[index];
+        let color = if pixel.weight <= MIN_WEIGHT { Color::black() } else { pixel.color * (1.0 / pixel.weight) };
+        if self.sample_count == 0 {
+            return color;
+        }
+        // Each sample's splats estimate the whole image, so they're shared out among all its pixels
+        let splat_scale = (self.width * self.height) as f64 / self.sample_count as f64;
+        color + self.splats[index] * splat_scale as f32
+    }
+
+    // Quantise the film into an 8-bit image
+    pub fn to_image(&self) -> DynamicImage {
+        let mut image = DynamicImage::new_rgb8(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                image.put_pixel(x, y, self.get_color(x, y).clamp().to_rgba());
+            }
+        }
+        image
+    }
+}
+
+#[test]
+fn test_pixel_variance() {
+    let mut variance = PixelVariance::default();
+    assert_eq!(variance.relative_error(), f64::INFINITY);
+    for &value in &[0.2, 0.4, 0.6, 0.8] {
+        variance.add(value);
+    }
+    assert_eq!(variance.count(), 4);
+    assert!((variance.mean() - 0.5).abs() < 1e-12);
+    assert!((variance.variance() - 0.2 / 3.0).abs() < 1e-12);
+
+    // Identical samples leave no doubt about the mean
+    let mut flat = PixelVariance::default();
+    flat.add(0.3);
+    flat.add(0.3);
+    assert_eq!(flat.relative_error(), 0.0);
+}
+
+#[test]
+fn test_film_splats_samples() {
+    use crate::filter::{BoxFilter, TriangleFilter};
+
+    let red = Color {red: 1.0, green: 0.0, blue: 0.0};
+    let blue = Color {red: 0.0, green: 0.0, blue: 1.0};
+
+    // A half-pixel box keeps each sample in its own pixel, where samples are averaged
+    let mut film = Film::new(4, 4);
+    film.add_sample(1.2, 1.7, red, &BoxFilter { radius: 0.5 });
+    film.add_sample(1.9, 1.1, blue, &BoxFilter { radius: 0.5 });
+    assert_eq!(film.get_color(1, 1), Color {red: 0.5, green: 0.0, blue: 0.5});
+    assert_eq!(film.get_color(0, 1), Color::black());
+
+    // A wider tent reaches the neighbours, weighting the nearer one more
+    let mut film = Film::new(4, 4);
+    film.add_sample(1.7, 1.5, red, &TriangleFilter { radius: 1.5 });
+    film.add_sample(2.5, 1.5, blue, &TriangleFilter { radius: 1.5 });
+    let mixed = film.get_color(1, 1);
+    assert!(mixed.red > mixed.blue && mixed.blue > 0.0);
+    let mixed = film.get_color(2, 1);
+    assert!(mixed.blue > mixed.red && mixed.red > 0.0);
+    // Samples never reach past the edge of the film
+    film.add_sample(0.1, 0.1, red, &TriangleFilter { radius: 1.5 });
+
+    // Splats land in one pixel, and count for less the more samples the image has taken
+    let mut film = Film::new(2, 1);
+    film.add_splat(Point2::new(1.5, 0.5), blue);
+    film.add_splat(Point2::new(2.5, 0.5), red);
+    for _ in 0..4 {
+        film.add_sample(0.5, 0.5, red, &BoxFilter { radius: 0.5 });
+    }
+    assert_eq!(film.get_color(0, 0), red);
+    assert_eq!(film.get_color(1, 0), Color {red: 0.0, green: 0.0, blue: 0.5});
+
+    // Samples in a filter's negative lobe can all but cancel out the pixel's weight, leaving nothing to
+    // divide by
+    struct Lobes;
+    impl Filter for Lobes {
+        fn radius(&self) -> f64 {
+            0.5
+        }
+        fn evaluate(&self, x: f64, _: f64) -> f64 {
+            if x < 0.0 { -0.9999 } else { 1.0 }
+        }
+    }
+    let mut film = Film::new(1, 1);
+    film.add_sample(0.25, 0.5, red, &Lobes);
+    film.add_sample(0.75, 0.5, blue, &Lobes);
+    assert_eq!(film.get_color(0, 0), Color::black());
+    film.add_sample(0.75, 0.5, blue, &Lobes);
+    assert_eq!(film.get_color(0, 0), Color::black());
+}
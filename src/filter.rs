@@ -0,0 +1,153 @@
+// Pixel reconstruction filters, which weight each sample's contribution to the pixels around it
+// REF: Pharr, Jakob and Humphreys, "Physically Based Rendering", 3rd ed., section 7.8
+
+use std::f64::consts::PI;
+
+pub trait Filter {
+    // How far, in pixels, a sample reaches from its position in each direction
+    fn radius(&self) -> f64;
+    // The weight of a sample offset (x, y) pixels from a pixel's center
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+// Every sample within `radius` counts equally. A radius of 0.5 averages each pixel's own samples.
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+// Weights fall off linearly to nothing at `radius`, also known as a tent filter
+pub struct TriangleFilter {
+    pub radius: f64,
+}
+
+// A Gaussian bell of falloff `alpha`, shifted down so it reaches zero at `radius`
+pub struct GaussianFilter {
+    pub radius: f64,
+    pub alpha: f64,
+}
+
+// Cubic filter trading blur (`b`) against ringing (`c`); b = c = 1/3 is the recommended balance
+// REF: Mitchell and Netravali, "Reconstruction Filters in Computer Graphics", 1988
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+// A sinc filter windowed by a wider sinc, `tau` lobes across
+pub struct LanczosFilter {
+    pub radius: f64,
+    pub tau: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        if x.abs() <= self.radius && y.abs() <= self.radius { 1.0 } else { 0.0 }
+    }
+}
+
+impl Filter for TriangleFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+    }
+}
+
+impl GaussianFilter {
+    fn gaussian(&self, d: f64) -> f64 {
+        ((-self.alpha * d * d).exp() - (-self.alpha * self.radius * self.radius).exp()).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+impl MitchellFilter {
+    // The 1D filter over x in (-1..1), scaled from the standard cubic's support of (-2..2)
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = (2.0 * x).abs();
+        let (b, c) = (self.b, self.c);
+        if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+        } else {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.mitchell(x / self.radius) * self.mitchell(y / self.radius)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+impl LanczosFilter {
+    fn windowed_sinc(&self, x: f64) -> f64 {
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.tau)
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.windowed_sinc(x) * self.windowed_sinc(y)
+    }
+}
+
+#[test]
+fn test_filters() {
+    let filters: Vec<Box<dyn Filter>> = vec![
+        Box::new(BoxFilter { radius: 0.5 }),
+        Box::new(TriangleFilter { radius: 2.0 }),
+        Box::new(GaussianFilter { radius: 1.5, alpha: 2.0 }),
+        Box::new(MitchellFilter { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+        Box::new(LanczosFilter { radius: 3.0, tau: 3.0 }),
+    ];
+    for filter in &filters {
+        // Strongest in the middle, symmetric, and nothing beyond the radius
+        let r = filter.radius();
+        assert!(filter.evaluate(0.0, 0.0) > 0.0);
+        assert!(filter.evaluate(0.0, 0.0) >= filter.evaluate(0.3, 0.1));
+        assert!((filter.evaluate(0.3, -0.2) - filter.evaluate(-0.3, 0.2)).abs() < 1e-12);
+        assert_eq!(filter.evaluate(r * 1.01, 0.0), 0.0);
+    }
+
+    // Mitchell-Netravali and Lanczos have negative lobes, which sharpen edges
+    assert!(filters[3].evaluate(1.5, 0.0) < 0.0);
+    assert!(filters[4].evaluate(1.5, 0.0) < 0.0);
+    // Lanczos passes through zero at every whole pixel
+    assert!(filters[4].evaluate(1.0, 0.0).abs() < 1e-12);
+}
@@ -0,0 +1,223 @@
+// Integrators compute the light arriving along a camera ray, each by a different rendering algorithm.
+// `render` takes samples and filters them into the image the same way whichever one the scene uses.
+
+use cgmath::{Point2, Vector3, InnerSpace};
+
+use crate::{cast_ray, Color, Intersection, Ray, Scene};
+use crate::bsdf::{cosine_hemisphere, Bsdf};
+use crate::camera::Eye;
+use crate::path_tracer::{emitted, emitter_pdf, power_heuristic, sample_lights};
+use crate::sampler::Sampler;
+
+pub trait Integrator {
+    // The light arriving along the ray, drawing whatever random numbers it needs from `sampler`
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color;
+
+    // As `radiance`, but the integrator may also add light to any pixel of the image through `splat`,
+    // given a position in pixel coordinates, as light tracing does
+    fn radiance_and_splats(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        _splat: &mut dyn FnMut(Point2<f64>, Color),
+    ) -> Color {
+        self.radiance(scene, ray, sampler)
+    }
+
+    // How many passes `render_film` makes over the image. Every pixel takes the sampler's samples per pixel
+    // in each pass, and the film averages them all.
+    fn passes(&self) -> u32 {
+        1
+    }
+
+    // Set up one pass over the image of `scene` seen from `eye`, for integrators that have to look at the
+    // whole scene first, such as by tracing photons. None, the default, renders each sample with
+    // `radiance_and_splats`.
+    fn begin_pass<'a>(&'a self, _scene: &'a Scene, _eye: Option<Eye>, _pass: u32) -> Option<Box<dyn RenderPass + 'a>> {
+        None
+    }
+}
+
+// What an integrator prepared for one pass over the image. It only lasts as long as the pass, so nothing
+// carries over from one render, or one eye, to the next.
+pub trait RenderPass {
+    fn radiance_and_splats(
+        &mut self,
+        scene: &Scene,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        splat: &mut dyn FnMut(Point2<f64>, Color),
+    ) -> Color;
+}
+
+// Classic recursive ray tracing: direct light from point, spot and directional lights, with perfect
+// mirror reflections and refractions followed up to the scene's max_recursion_depth
+pub struct WhittedIntegrator;
+
+// How much of the hemisphere above each visible point is open rather than blocked by nearby geometry,
+// ignoring the lights entirely. Blockers further away than `max_distance` don't count.
+pub struct AmbientOcclusionIntegrator {
+    pub max_distance: f64,
+}
+
+// Light arriving at visible surfaces straight from the lights, including through mirrors and glass,
+// without any diffuse interreflection
+pub struct DirectLightingIntegrator;
+
+// What a debug integrator shows at each visible point, instead of its lighting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugView {
+    Normal,                         // the surface normal, mapped from (-1..1) to (0..1) per axis
+    Uv,                             // texture coordinates as red and green
+    Depth { max_distance: f64 },    // white up close, fading to black at max_distance
+    Albedo,                         // the diffuse color, unlit
+}
+
+pub struct DebugIntegrator {
+    pub view: DebugView,
+}
+
+impl Integrator for WhittedIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, _sampler: &mut dyn Sampler) -> Color {
+        cast_ray(scene, ray, 0)
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        let hit = match scene.trace(ray) {
+            Some(hit) => hit,
+            None => return Color::black(),
+        };
+        // Cosine-weighted directions make the fraction of unblocked rays the cosine-weighted openness
+        let normal = if hit.normal.dot(ray.direction) > 0.0 { -hit.normal } else { hit.normal };
+        let direction = cosine_hemisphere(normal, sampler.get_2d());
+        let probe = Ray::create_bounce(normal, direction, hit.point, scene.shadow_bias);
+        if scene.occluded(&probe, self.max_distance) { Color::black() } else { Color::white() }
+    }
+}
+
+impl Integrator for DirectLightingIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        trace_direct(scene, ray, sampler, &|_, _, _| Color::black())
+    }
+}
+
+// Follow a camera ray through mirrors and glass, adding the light reaching each rough surface it meets
+// straight from the lights, plus whatever `indirect` estimates arrives there by any other route
+pub fn trace_direct(
+    scene: &Scene,
+    ray: &Ray,
+    sampler: &mut dyn Sampler,
+    indirect: &dyn Fn(&Intersection, &Bsdf, Vector3<f64>) -> Color,
+) -> Color {
+    let emitters = scene.emitters();
+    let mut radiance = Color::black();
+    let mut throughput = Color::white();
+    let mut ray = Ray { origin: ray.origin, direction: ray.direction };
+
+    for _ in 0..=scene.max_recursion_depth {
+        let hit = match scene.trace(&ray) {
+            Some(hit) => hit,
+            None => break,
+        };
+        let wo = -ray.direction;
+        // Only reached straight from the camera or through mirrors and glass, so counted in full
+        radiance = radiance + throughput * emitted(&hit, wo);
+
+        let light_choice = sampler.get_1d();
+        let light_sample = sampler.get_2d();
+        let lobe = sampler.get_1d();
+        let direction_sample = sampler.get_2d();
+
+        let bsdf = Bsdf::new(&hit);
+        if bsdf.is_rough() {
+            radiance = radiance + throughput * sample_lights(scene, &hit, &bsdf, wo, light_choice, light_sample);
+            radiance = radiance + throughput * indirect(&hit, &bsdf, wo);
+        }
+        let sample = match bsdf.sample(wo, lobe, direction_sample) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let bounce = Ray::create_bounce(hit.normal, sample.direction, hit.point, scene.shadow_bias);
+        if !sample.specular {
+            // Following the BSDF finds the emitters sampled above too; multiple importance sampling
+            // shares their light between the two, and then the path ends
+            if let Some(next) = scene.trace(&bounce) {
+                let emission = emitted(&next, -bounce.direction);
+                if emission != Color::black() {
+                    let weight = power_heuristic(sample.pdf, emitter_pdf(emitters.len(), &next, bounce.origin));
+                    radiance = radiance + throughput * sample.weight * emission * weight as f32;
+                }
+            }
+            break;
+        }
+        throughput = throughput * sample.weight;
+        ray = bounce;
+    }
+    radiance
+}
+
+impl Integrator for DebugIntegrator {
+    fn radiance(&self, scene: &Scene, ray: &Ray, _sampler: &mut dyn Sampler) -> Color {
+        let hit = match scene.trace(ray) {
+            Some(hit) => hit,
+            None => return Color::black(),
+        };
+        match self.view {
+            DebugView::Normal => {
+                let n = (hit.normal + Vector3::new(1.0, 1.0, 1.0)) * 0.5;
+                Color { red: n.x as f32, green: n.y as f32, blue: n.z as f32 }
+            }
+            DebugView::Uv => Color { red: hit.uv.x as f32, green: hit.uv.y as f32, blue: 0.0 },
+            DebugView::Depth { max_distance } => {
+                let brightness = (1.0 - hit.t / max_distance).max(0.0) as f32;
+                Color { red: brightness, green: brightness, blue: brightness }
+            }
+            DebugView::Albedo => hit.object.material().color_at(hit.uv),
+        }
+    }
+}
+
+#[test]
+fn test_integrators() {
+    use cgmath::Point3;
+    use crate::{estimate_radiance, lamp_scene, Sphere};
+    use crate::material::Material;
+    use crate::path_tracer::PathTracer;
+
+    // The lamp scene with a ball off to the side
+    let gray = Color {red: 0.5, green: 0.5, blue: 0.5};
+    let scene = lamp_scene(vec![
+        Box::new(Sphere {
+            center: Point3::new(5.0, 1.0, 0.0),
+            radius: 1.0,
+            material: Material::matte(gray),
+        }),
+    ]);
+    let estimate = |integrator: &dyn Integrator, ray: &Ray| estimate_radiance(&scene, integrator, ray, 1024);
+    let down = |x: f64| Ray { origin: Point3::new(x, 0.5, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+
+    // Directly under the lamp, only direct light reaches the floor: a quarter of the lamp's brightness
+    // (see lamp_scene), whichever way it's computed
+    let direct = estimate(&DirectLightingIntegrator, &down(0.0));
+    assert!((direct.red - 0.25).abs() < 0.01, "{:?}", direct);
+    let path = estimate(&PathTracer { roulette_depth: 3 }, &down(0.0));
+    assert!((direct.red - path.red).abs() < 0.01);
+    // Whitted shading knows nothing of area lights
+    assert_eq!(estimate(&WhittedIntegrator, &down(0.0)), Color::black());
+
+    // Floor beside the ball is partly hidden from the sky; floor in the open isn't
+    let occlusion = AmbientOcclusionIntegrator { max_distance: 10.0 };
+    assert_eq!(estimate(&occlusion, &down(-20.0)), Color::white());
+    let beside_ball = estimate(&occlusion, &down(3.5)).red;
+    assert!(beside_ball > 0.5 && beside_ball < 1.0);
+
+    // Floor normals point straight up, and the floor is 0.5 below the ray's origin
+    let normal = estimate(&DebugIntegrator { view: DebugView::Normal }, &down(2.0));
+    assert_eq!(normal, Color {red: 0.5, green: 1.0, blue: 0.5});
+    let depth = estimate(&DebugIntegrator { view: DebugView::Depth { max_distance: 2.0 } }, &down(2.0));
+    assert_eq!(depth, Color {red: 0.75, green: 0.75, blue: 0.75});
+    assert_eq!(estimate(&DebugIntegrator { view: DebugView::Albedo }, &down(2.0)), gray);
+}
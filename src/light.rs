@@ -0,0 +1,118 @@
+use std::f32::consts::PI;
+
+use cgmath::{Point3, Vector3, InnerSpace, MetricSpace};
+
+use crate::Color;
+
+// A light infinitely far away, shining along `direction` with the same intensity everywhere
+pub struct DirectionalLight {
+    pub direction: Vector3<f64>,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+// A light radiating equally in all directions from `position`
+pub struct PointLight {
+    pub position: Point3<f64>,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+// A point light restricted to a cone around `direction`. `angle` is the cone's half-angle in degrees;
+// over the outermost `penumbra` degrees of it the light fades smoothly to nothing.
+pub struct SpotLight {
+    pub position: Point3<f64>,
+    pub direction: Vector3<f64>,
+    pub angle: f64,
+    pub penumbra: f64,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+pub enum Light {
+    Directional(DirectionalLight),
+    Point(PointLight),
+    Spot(SpotLight),
+}
+
+impl Light {
+    pub fn color(&self) -> &Color {
+        match self {
+            Light::Directional(d) => &d.color,
+            Light::Point(p) => &p.color,
+            Light::Spot(s) => &s.color,
+        }
+    }
+
+    // Unit vector from `point` towards the light
+    pub fn direction_from(&self, point: &Point3<f64>) -> Vector3<f64> {
+        match self {
+            Light::Directional(d) => -d.direction.normalize(),
+            Light::Point(p) => (p.position - point).normalize(),
+            Light::Spot(s) => (s.position - point).normalize(),
+        }
+    }
+
+    // How far the light is from `point`; directional lights are infinitely far away
+    pub fn distance(&self, point: &Point3<f64>) -> f64 {
+        match self {
+            Light::Directional(_) => f64::INFINITY,
+            Light::Point(p) => p.position.distance(*point),
+            Light::Spot(s) => s.position.distance(*point),
+        }
+    }
+
+    // The light's intensity on arrival at `point`
+    pub fn intensity(&self, point: &Point3<f64>) -> f32 {
+        match self {
+            Light::Directional(d) => d.intensity,
+            Light::Point(p) => {
+                // Inverse-square falloff: the power is spread over a sphere of radius r
+                let r2 = p.position.distance2(*point) as f32;
+                p.intensity / (4.0 * PI * r2)
+            }
+            Light::Spot(s) => {
+                let r2 = s.position.distance2(*point) as f32;
+                let cos_theta = (point - s.position).normalize().dot(s.direction.normalize());
+                let cos_outer = s.angle.to_radians().cos();
+                let cos_inner = (s.angle - s.penumbra).max(0.0).to_radians().cos();
+                s.intensity * smoothstep(cos_outer, cos_inner, cos_theta) as f32 / (4.0 * PI * r2)
+            }
+        }
+    }
+}
+
+// Hermite interpolation from 0 at `edge0` to 1 at `edge1`
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[test]
+fn test_light_intensity() {
+    let white = Color {red: 1.0, green: 1.0, blue: 1.0};
+    let point = Light::Point(PointLight { position: Point3::new(0.0, 0.0, 0.0), color: white, intensity: 100.0 });
+    // Twice the distance, a quarter of the light
+    let near = point.intensity(&Point3::new(0.0, 1.0, 0.0));
+    let far = point.intensity(&Point3::new(0.0, 2.0, 0.0));
+    assert!((near / far - 4.0).abs() < 1e-4);
+
+    let spot = Light::Spot(SpotLight {
+        position: Point3::new(0.0, 0.0, 0.0),
+        direction: Vector3::new(0.0, -1.0, 0.0),
+        angle: 30.0,
+        penumbra: 10.0,
+        color: white,
+        intensity: 100.0,
+    });
+    // Full strength inside the inner cone, fading through the penumbra, dark outside the cone
+    let on_axis = spot.intensity(&Point3::new(0.0, -1.0, 0.0));
+    assert!((on_axis - near).abs() < 1e-6);
+    let edge = Point3::new(25.0f64.to_radians().tan(), -1.0, 0.0);
+    let in_penumbra = spot.intensity(&edge) * Point3::new(0.0, 0.0, 0.0).distance2(edge) as f32;
+    assert!(in_penumbra > 0.0 && in_penumbra < on_axis);
+    assert_eq!(spot.intensity(&Point3::new(1.0, -1.0, 0.0)), 0.0);
+}
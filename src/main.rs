@@ -1,11 +1,41 @@
 extern crate image;
 extern crate cgmath;
+extern crate rand;
 
-use cgmath::{Point3, Vector3, InnerSpace};
-use image::{DynamicImage, GenericImage, GenericImageView, Rgba, Pixel};
+pub mod bidirectional;
+pub mod bsdf;
+pub mod bvh;
+pub mod camera;
+pub mod film;
+pub mod filter;
+pub mod integrator;
+pub mod light;
+pub mod material;
+pub mod mesh;
+pub mod metropolis;
+pub mod obj;
+pub mod path_tracer;
+pub mod photon_map;
+pub mod sampler;
+
+use cgmath::{Point2, Point3, Vector3, InnerSpace};
+use std::f64::consts::PI;
+use std::ops::{Add, Mul};
+
+use bvh::{Aabb, Aggregate, Bounded};
+use camera::{Camera, Eye, FovAxis, Projection, StereoLayout};
+use film::Film;
+use filter::{Filter, MitchellFilter};
+use light::{DirectionalLight, Light, PointLight, SpotLight};
+use integrator::Integrator;
+use material::Material;
+use path_tracer::PathTracer;
+use image::{DynamicImage, GenericImage, Rgba, Pixel};
+use sampler::{AdaptiveSampling, Sampler, SobolSampler};
 
 // REF: https://bheisler.github.io/post/writing-raytracer-in-rust-part-1/
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Color {
     pub red: f32,
     pub green: f32,
@@ -23,6 +53,12 @@ fn gamma_decode(encoded: f32) -> f32 {
 }
 
 impl Color {
+    pub fn black() -> Color {
+        Color { red: 0.0, green: 0.0, blue: 0.0 }
+    }
+
+    // Not f32::clamp, which passes NaN through; this way a NaN channel saturates to 1.0
+    #[allow(clippy::manual_clamp)]
     pub fn clamp(&self) -> Color {
         Color {
             red: self.red.min(1.0).max(0.0),
@@ -31,6 +67,15 @@ impl Color {
         }
     }
 
+    pub fn white() -> Color {
+        Color { red: 1.0, green: 1.0, blue: 1.0 }
+    }
+
+    // Perceived brightness, weighting the channels as Rec. 709 does
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
+    }
+
     pub fn to_rgba(&self) -> Rgba<u8> {
         Rgba::from_channels(
             (gamma_encode(self.red) * 255.0) as u8,
@@ -51,54 +96,566 @@ impl Color {
     }
 }
 
+impl Add for Color {
+    type Output = Color;
+
+    fn add(self, other: Color) -> Color {
+        Color {
+            red: self.red + other.red,
+            green: self.green + other.green,
+            blue: self.blue + other.blue,
+        }
+    }
+}
+
+impl Mul for Color {
+    type Output = Color;
+
+    fn mul(self, other: Color) -> Color {
+        Color {
+            red: self.red * other.red,
+            green: self.green * other.green,
+            blue: self.blue * other.blue,
+        }
+    }
+}
+
+impl Mul<f32> for Color {
+    type Output = Color;
+
+    fn mul(self, other: f32) -> Color {
+        Color {
+            red: self.red * other,
+            green: self.green * other,
+            blue: self.blue * other,
+        }
+    }
+}
+
 
 pub struct Sphere {
     pub center: Point3<f64>,
     pub radius: f64,
-    pub color: Color
+    pub material: Material
+}
+
+// An infinite plane through `point`. One-sided planes can only be hit from the side `normal` faces.
+pub struct Plane {
+    pub point: Point3<f64>,
+    pub normal: Vector3<f64>,
+    pub one_sided: bool,
+    pub material: Material
+}
+
+// A flat circle of `radius` around `center`, facing along `normal`
+pub struct Disk {
+    pub center: Point3<f64>,
+    pub normal: Vector3<f64>,
+    pub radius: f64,
+    pub material: Material
 }
 
 pub struct Scene {
     pub width: u32,
     pub height: u32,
-    pub fov: f64,
-    pub sphere: Sphere
+    pub camera: Camera,
+    pub objects: Aggregate<dyn Intersectable>,
+    pub lights: Vec<Light>,
+    pub shadow_bias: f64,       // how far shadow rays start off the surface, to avoid shadow acne
+    pub max_recursion_depth: u32,   // how many times a ray may bounce before it's given up on
+    pub sampler: Box<dyn Sampler>,  // where each pixel's samples go, and how many it takes
+    pub adaptive_sampling: Option<AdaptiveSampling>,    // extra samples for noisy pixels, if any
+    pub filter: Box<dyn Filter>,    // how samples are weighted into the pixels around them
+    pub integrator: Box<dyn Integrator>     // the algorithm that finds the light arriving along each ray
+}
+
+impl Scene {
+    // Find the closest intersection of the ray with any object in the scene
+    pub fn trace(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        self.objects.intersect(ray)
+    }
+
+    // Is there anything along the ray before max_distance?
+    pub fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.objects.occluded(ray, max_distance)
+    }
+
+    // Objects that give off light and can be sampled as area lights, as found when the objects were gathered
+    pub fn emitters(&self) -> Emitters<'_> {
+        Emitters { objects: &self.objects.objects, indices: self.objects.emitter_indices() }
+    }
+}
+
+// The scene's emitters, borrowed from its objects so that finding them costs nothing per sample
+#[derive(Clone, Copy)]
+pub struct Emitters<'a> {
+    objects: &'a [Box<dyn Intersectable>],
+    indices: &'a [usize],
+}
+
+impl<'a> Emitters<'a> {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> &'a dyn Intersectable {
+        self.objects[self.indices[index]].as_ref()
+    }
+}
+
+// Light the hit point with every light in the scene that it can see, following Lambert's cosine law
+fn get_color(scene: &Scene, hit: &Intersection, normal: Vector3<f64>) -> Color {
+    // A Lambertian surface reflects albedo / pi of the incoming light in every direction
+    let reflected = hit.object.material().color_at(hit.uv) * std::f32::consts::FRAC_1_PI;
+
+    let mut color = Color::black();
+    for light in &scene.lights {
+        let direction_to_light = light.direction_from(&hit.point);
+        let cosine = normal.dot(direction_to_light).max(0.0) as f32;
+        if cosine == 0.0 {
+            continue;
+        }
+
+        // Start the shadow ray just off the surface so it can't hit the surface it leaves. Only
+        // objects between the point and the light block it, not those beyond the light.
+        let shadow_ray = Ray {
+            origin: hit.point + normal * scene.shadow_bias,
+            direction: direction_to_light,
+        };
+        if scene.occluded(&shadow_ray, light.distance(&shadow_ray.origin)) {
+            continue;
+        }
+
+        color = color + reflected * *light.color() * (light.intensity(&hit.point) * cosine);
+    }
+    color
+}
+
+// Shade a hit, blending in whatever its mirror reflection sees and, for transparent materials,
+// whatever is seen through it
+fn shade(scene: &Scene, ray: &Ray, hit: &Intersection, depth: u32) -> Color {
+    let material = hit.object.material();
+    // Surfaces are lit on whichever side the ray arrived from
+    let normal = if hit.normal.dot(ray.direction) > 0.0 { -hit.normal } else { hit.normal };
+    let mut color = path_tracer::emitted(hit, -ray.direction) + get_color(scene, hit, normal);
+
+    if material.reflectivity > 0.0 {
+        let reflection_ray = Ray::create_reflection(normal, ray.direction, hit.point, scene.shadow_bias);
+        color = color * (1.0 - material.reflectivity) + cast_ray(scene, &reflection_ray, depth + 1) * material.reflectivity;
+    }
+
+    let transparency = 1.0 - material.opacity;
+    if transparency > 0.0 {
+        // The Fresnel equations split the light between the reflected and transmitted rays
+        let kr = fresnel(ray.direction, hit.normal, material.refractive_index);
+        let reflection_ray = Ray::create_reflection(normal, ray.direction, hit.point, scene.shadow_bias);
+        let reflection_color = cast_ray(scene, &reflection_ray, depth + 1);
+        // Past the critical angle everything is reflected and there's no transmitted ray
+        let refraction_color = Ray::create_transmission(hit.normal, ray.direction, hit.point, scene.shadow_bias, material.refractive_index)
+            .map_or(Color::black(), |transmission_ray| cast_ray(scene, &transmission_ray, depth + 1));
+        let kr = kr as f32;
+        color = color * material.opacity + (reflection_color * kr + refraction_color * (1.0 - kr)) * transparency;
+    }
+    color
+}
+
+// Fraction of light reflected (rather than transmitted) where a ray meets a dielectric surface.
+// `normal` points out of the object, so rays travelling along it are leaving the object.
+// REF: https://www.scratchapixel.com/lessons/3d-basic-rendering/introduction-to-shading/reflection-refraction-fresnel
+pub fn fresnel(incident: Vector3<f64>, normal: Vector3<f64>, index: f64) -> f64 {
+    let i_dot_n = incident.dot(normal);
+    let (eta_i, eta_t) = if i_dot_n > 0.0 { (index, 1.0) } else { (1.0, index) };
+
+    // Snell's law gives the sine of the transmitted angle; above 1 there's total internal reflection
+    let sin_t = eta_i / eta_t * (1.0 - i_dot_n * i_dot_n).max(0.0).sqrt();
+    if sin_t > 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+    let cos_i = i_dot_n.abs();
+    // Average the reflectance of s- and p-polarised light
+    let r_s = ((eta_t * cos_i) - (eta_i * cos_t)) / ((eta_t * cos_i) + (eta_i * cos_t));
+    let r_p = ((eta_i * cos_i) - (eta_t * cos_t)) / ((eta_i * cos_i) + (eta_t * cos_t));
+    (r_s * r_s + r_p * r_p) / 2.0
 }
 
-pub fn render(scene: &Scene) -> DynamicImage {
-    let mut image = DynamicImage::new_rgb8(scene.width, scene.height);
-    let black = Rgba::from_channels(0, 0, 0, 0);
-    for x in 0..scene.width {
-        for y in 0..scene.height {
-            let ray = Ray::create_prime(x, y, scene);
+// Find the color seen along a ray. `depth` counts how many bounces led here, and once it passes
+// the scene's max_recursion_depth the path ends.
+pub fn cast_ray(scene: &Scene, ray: &Ray, depth: u32) -> Color {
+    if depth > scene.max_recursion_depth {
+        return Color::black();
+    }
 
-            if scene.sphere.intersect(&ray) {
-                image.put_pixel(x, y, scene.sphere.color.to_rgba())
-            } else {
-                image.put_pixel(x, y, black);
+    match scene.trace(ray) {
+        Some(hit) => shade(scene, ray, &hit, depth),
+        None => Color::black(),
+    }
+}
+
+// Render the view from one eye of a stereo camera, or the single view of a mono camera (eye None),
+// into a film that still holds each pixel's sample statistics
+pub fn render_film(scene: &Scene, eye: Option<Eye>) -> Film {
+    let mut film = Film::new(scene.width, scene.height);
+    let mut sampler = scene.sampler.clone_sampler();
+    let min_samples = sampler.samples_per_pixel();
+    // How many samples each pixel has taken in earlier passes, so later ones carry on through the sequence
+    let mut samples_taken = vec![0; (scene.width * scene.height) as usize];
+    for pass in 0..scene.integrator.passes() {
+        let mut render_pass = scene.integrator.begin_pass(scene, eye, pass);
+        for x in 0..scene.width {
+            for y in 0..scene.height {
+                // Each sample goes through a different part of the pixel and a different point on the camera's lens
+                let first = samples_taken[(y * scene.width + x) as usize];
+                let mut index = 0;
+                loop {
+                    let done = match scene.adaptive_sampling {
+                        _ if index < min_samples => false,
+                        Some(adaptive) => {
+                            index >= adaptive.max_samples || film.variance(x, y).relative_error() <= adaptive.threshold
+                        }
+                        None => true,
+                    };
+                    if done {
+                        break;
+                    }
+
+                    sampler.start_pixel_sample((x, y), first + index);
+                    let offset = sampler.get_2d();
+                    let (sample_x, sample_y) = (x as f64 + offset.x, y as f64 + offset.y);
+                    let lens_sample = sampler.get_2d();
+                    // Points the camera's projection doesn't cover stay black
+                    let color = match Ray::create_prime(sample_x, sample_y, scene, lens_sample, eye) {
+                        Some(ray) => {
+                            let splat = &mut |position, color| film.add_splat(position, color);
+                            match &mut render_pass {
+                                Some(render_pass) => render_pass.radiance_and_splats(scene, &ray, &mut *sampler, splat),
+                                None => scene.integrator.radiance_and_splats(scene, &ray, &mut *sampler, splat),
+                            }
+                        }
+                        None => Color::black(),
+                    };
+                    film.add_sample(sample_x, sample_y, color, &*scene.filter);
+                    index += 1;
+                }
+                samples_taken[(y * scene.width + x) as usize] += index;
             }
         }
     }
-    image
+    film
+}
+
+// Render the view from one eye of a stereo camera, or the single view of a mono camera (eye None)
+pub fn render_eye(scene: &Scene, eye: Option<Eye>) -> DynamicImage {
+    render_film(scene, eye).to_image()
+}
+
+// Render the left and right eyes of a stereo camera as separate images
+pub fn render_eyes(scene: &Scene) -> (DynamicImage, DynamicImage) {
+    (render_eye(scene, Some(Eye::Left)), render_eye(scene, Some(Eye::Right)))
+}
+
+// A rendered scene: one image, or one for each eye when a stereo camera keeps them separate
+pub enum Rendered {
+    Image(DynamicImage),
+    Eyes(DynamicImage, DynamicImage),
+}
+
+// Render the scene. Stereo cameras pack both eyes into one image, side by side or top-bottom, unless
+// their layout asks for the eyes as separate images.
+pub fn render(scene: &Scene) -> Rendered {
+    let stereo = match scene.camera.stereo {
+        Some(stereo) => stereo,
+        None => return Rendered::Image(render_eye(scene, None)),
+    };
+
+    let (left, right) = render_eyes(scene);
+    let (right_x, right_y) = match stereo.layout {
+        StereoLayout::SideBySide => (scene.width, 0),
+        StereoLayout::TopBottom => (0, scene.height),
+        StereoLayout::Separate => return Rendered::Eyes(left, right),
+    };
+    let mut image = DynamicImage::new_rgb8(right_x + scene.width, right_y + scene.height);
+    image.copy_from(&left, 0, 0);
+    image.copy_from(&right, right_x, right_y);
+    Rendered::Image(image)
+}
+
+// A one pixel scene of the given objects with no lights, shaded by Whitted ray tracing, for tests to
+// change what they need
+#[cfg(test)]
+pub fn test_scene(objects: Vec<Box<dyn Intersectable>>) -> Scene {
+    Scene {
+        width: 1,
+        height: 1,
+        camera: Camera::default(),
+        objects: Aggregate::new(objects),
+        lights: vec![],
+        shadow_bias: 1e-4,
+        max_recursion_depth: 5,
+        sampler: Box::new(sampler::IndependentSampler::new(1, 0)),
+        adaptive_sampling: None,
+        filter: Box::new(filter::BoxFilter { radius: 0.5 }),
+        integrator: Box::new(integrator::WhittedIntegrator)
+    }
+}
+
+// A grey floor under a round lamp of radius 1, one unit up and facing down, along with any extra objects.
+// Directly under the lamp the floor's irradiance is pi L r^2 / (h^2 + r^2), so its radiance is
+// albedo L r^2 / (h^2 + r^2): a quarter of the lamp's.
+#[cfg(test)]
+pub fn lamp_scene(extra: Vec<Box<dyn Intersectable>>) -> Scene {
+    let mut objects: Vec<Box<dyn Intersectable>> = vec![
+        Box::new(Plane {
+            point: Point3::new(0.0, 0.0, 0.0),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            one_sided: false,
+            material: Material::matte(Color {red: 0.5, green: 0.5, blue: 0.5})
+        }),
+        Box::new(Disk {
+            center: Point3::new(0.0, 1.0, 0.0),
+            normal: Vector3::new(0.0, -1.0, 0.0),
+            radius: 1.0,
+            material: Material::emissive(Color::white())
+        }),
+    ];
+    objects.extend(extra);
+    test_scene(objects)
+}
+
+// The average radiance an integrator finds along a ray over `samples` samples, spread over its passes as
+// rendering does
+#[cfg(test)]
+pub fn estimate_radiance(scene: &Scene, integrator: &dyn Integrator, ray: &Ray, samples: u32) -> Color {
+    let mut sampler = SobolSampler::new(samples, 1);
+    let mut total = Color::black();
+    let passes = integrator.passes();
+    for pass in 0..passes {
+        let mut render_pass = integrator.begin_pass(scene, None, pass);
+        for index in pass * samples / passes..(pass + 1) * samples / passes {
+            sampler.start_pixel_sample((0, 0), index);
+            let radiance = match &mut render_pass {
+                Some(render_pass) => render_pass.radiance_and_splats(scene, ray, &mut sampler, &mut |_, _| {}),
+                None => integrator.radiance(scene, ray, &mut sampler),
+            };
+            total = total + radiance * (1.0 / samples as f32);
+        }
+    }
+    total
 }
 
 #[test]
 fn test_can_render_scene() {
-    let scene = Scene {
-        width: 800,
-        height: 600,
-        fov: 90.0,
-        sphere: Sphere {
+    use image::GenericImageView;
+    use camera::Stereo;
+
+    let mut scene = test_scene(vec![
+        Box::new(Sphere {
             center: Point3 {x: 0.0, y: 0.0, z: -5.0},
             radius: 1.0,
-            color: Color {red: 0.4, green: 1.0, blue: 0.4}
-        }
-    };
+            material: Material::matte(Color {red: 0.4, green: 1.0, blue: 0.4})
+        }),
+    ]);
+    scene.width = 800;
+    scene.height = 600;
+    scene.lights = vec![
+        Light::Directional(DirectionalLight {
+            direction: Vector3 {x: 0.0, y: 0.0, z: -1.0},
+            color: Color {red: 1.0, green: 1.0, blue: 1.0},
+            intensity: 20.0
+        }),
+    ];
 
-    let img: DynamicImage = render(&scene);
+    let single = |rendered| match rendered {
+        Rendered::Image(img) => img,
+        Rendered::Eyes(..) => panic!("expected a single image"),
+    };
+    let img: DynamicImage = single(render(&scene));
     assert_eq!(scene.width, img.width());
     assert_eq!(scene.height, img.height());
 
+    // Stereo frames hold both eyes
+    let stereo = Stereo { eye_separation: 0.064, convergence_distance: 5.0, layout: StereoLayout::TopBottom };
+    let mut scene = Scene { camera: Camera { stereo: Some(stereo), ..Camera::default() }, width: 80, height: 60, ..scene };
+    let img: DynamicImage = single(render(&scene));
+    assert_eq!(scene.width, img.width());
+    assert_eq!(scene.height * 2, img.height());
+
+    // ...unless they're asked for separately
+    scene.camera.stereo = Some(Stereo { layout: StereoLayout::Separate, ..stereo });
+    match render(&scene) {
+        Rendered::Eyes(left, right) => {
+            assert_eq!((left.width(), left.height()), (scene.width, scene.height));
+            assert_eq!((right.width(), right.height()), (scene.width, scene.height));
+        }
+        Rendered::Image(_) => panic!("expected an image for each eye"),
+    }
+}
+
+#[test]
+fn test_color_clamp() {
+    let color = Color { red: 1.5, green: -0.5, blue: f32::NAN }.clamp();
+    assert_eq!(color, Color { red: 1.0, green: 0.0, blue: 1.0 });
+}
+
+#[test]
+fn test_trace_finds_nearest_object() {
+    let scene = test_scene(vec![
+        Box::new(Sphere {
+            center: Point3 {x: 0.0, y: 0.0, z: -10.0},
+            radius: 1.0,
+            material: Material::matte(Color {red: 1.0, green: 0.0, blue: 0.0})
+        }),
+        Box::new(Sphere {
+            center: Point3 {x: 0.0, y: 0.0, z: -5.0},
+            radius: 1.0,
+            material: Material::matte(Color {red: 0.0, green: 0.0, blue: 1.0})
+        }),
+    ]);
+
+    let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    let hit = scene.trace(&ray).unwrap();
+    assert!((hit.t - 4.0).abs() < 1e-9);
+    assert_eq!(hit.object.material().color.blue, 1.0);
+}
+
+#[test]
+fn test_shadows() {
+    let scene = test_scene(vec![
+        Box::new(Sphere {
+            center: Point3 {x: 0.0, y: 2.0, z: 0.0},
+            radius: 1.0,
+            material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+        }),
+        Box::new(Plane {
+            point: Point3 {x: 0.0, y: 0.0, z: 0.0},
+            normal: Vector3 {x: 0.0, y: 1.0, z: 0.0},
+            one_sided: false,
+            material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+        }),
+    ]);
+
+    let up = Ray { origin: Point3::new(0.0, 1e-4, 0.0), direction: Vector3::new(0.0, 1.0, 0.0) };
+    // A light above the sphere is blocked by it...
+    assert!(scene.occluded(&up, 5.0));
+    // ...but one between the sphere and the ground isn't
+    assert!(!scene.occluded(&up, 0.5));
+}
+
+#[test]
+fn test_reflection() {
+    // A mirror facing the camera, and a red sphere behind the camera that only the mirror can see
+    let mut scene = test_scene(vec![
+        Box::new(Plane {
+            point: Point3 {x: 0.0, y: 0.0, z: -5.0},
+            normal: Vector3 {x: 0.0, y: 0.0, z: 1.0},
+            one_sided: true,
+            material: Material::reflective(Color {red: 0.0, green: 0.0, blue: 0.0}, 1.0)
+        }),
+        Box::new(Sphere {
+            center: Point3 {x: 0.0, y: 0.0, z: 5.0},
+            radius: 1.0,
+            material: Material::matte(Color {red: 1.0, green: 0.0, blue: 0.0})
+        }),
+    ]);
+    scene.lights = vec![
+        Light::Point(PointLight {
+            position: Point3 {x: 0.0, y: 3.0, z: 2.0},
+            color: Color {red: 1.0, green: 1.0, blue: 1.0},
+            intensity: 1000.0
+        }),
+    ];
+    scene.max_recursion_depth = 1;
+
+    let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    let color = cast_ray(&scene, &ray, 0);
+    assert!(color.red > 0.0 && color.green == 0.0);
+
+    // With no bounces allowed the mirror shows nothing
+    scene.max_recursion_depth = 0;
+    assert_eq!(cast_ray(&scene, &ray, 0), Color::black());
+}
+
+#[test]
+fn test_refraction() {
+    let normal = Vector3::new(0.0, 1.0, 0.0);
+    let point = Point3::new(0.0, 0.0, 0.0);
+
+    // Head-on, glass reflects about 4% and passes straight through
+    let down = Vector3::new(0.0, -1.0, 0.0);
+    assert!((fresnel(down, normal, 1.5) - 0.04).abs() < 1e-9);
+    let ray = Ray::create_transmission(normal, down, point, 1e-4, 1.5).unwrap();
+    assert!((ray.direction - down).magnitude() < 1e-9);
+    assert!(ray.origin.y < 0.0);
+
+    // Entering at 45 degrees, the ray bends towards the normal by Snell's law
+    let incident = Vector3::new(1.0, -1.0, 0.0).normalize();
+    let ray = Ray::create_transmission(normal, incident, point, 1e-4, 1.5).unwrap();
+    let sin_t = ray.direction.x;
+    assert!((sin_t * 1.5 - incident.x).abs() < 1e-9);
+
+    // Leaving at the same angle from inside the glass is past the critical angle
+    let outgoing = Vector3::new(1.0, 1.0, 0.0).normalize();
+    assert!(Ray::create_transmission(normal, outgoing, point, 1e-4, 1.5).is_none());
+    assert_eq!(fresnel(outgoing, normal, 1.5), 1.0);
+
+    // A shallower exit gets out, bending away from the normal and starting outside the object
+    let outgoing = Vector3::new(0.3, 1.0, 0.0).normalize();
+    let ray = Ray::create_transmission(normal, outgoing, point, 1e-4, 1.5).unwrap();
+    assert!((ray.direction.x - outgoing.x * 1.5).abs() < 1e-9);
+    assert!(ray.origin.y > 0.0);
+}
+
+#[test]
+fn test_prime_rays_for_any_aspect_ratio() {
+    for &(width, height) in &[(160, 90), (100, 100), (90, 160)] {
+        let scene = Scene { width, height, ..test_scene(vec![]) };
+        // Pixels are square: the rays through horizontally and vertically adjacent pixels are equally far apart
+        let (cx, cy) = ((width / 2) as f64 + 0.5, (height / 2) as f64 + 0.5);
+        let center = Ray::create_prime(cx, cy, &scene, Point2::new(0.5, 0.5), None).unwrap().direction;
+        let across = Ray::create_prime(cx + 1.0, cy, &scene, Point2::new(0.5, 0.5), None).unwrap().direction;
+        let down = Ray::create_prime(cx, cy + 1.0, &scene, Point2::new(0.5, 0.5), None).unwrap().direction;
+        assert!(((center - across).magnitude() - (center - down).magnitude()).abs() < 1e-6);
+
+        // The top edge is at the top of the 90 degree vertical field of view
+        let top = Ray::create_prime(width as f64 / 2.0, 0.0, &scene, Point2::new(0.5, 0.5), None).unwrap().direction;
+        let angle = top.y.atan2(-top.z).to_degrees();
+        assert!((angle - 45.0).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_adaptive_sampling() {
+    // A small sphere against an empty background: only pixels along its edge are noisy
+    let mut scene = test_scene(vec![
+        Box::new(Sphere {
+            center: Point3 {x: 0.0, y: 0.0, z: -5.0},
+            radius: 2.0,
+            material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+        }),
+    ]);
+    scene.width = 16;
+    scene.height = 16;
+    scene.lights = vec![Light::Directional(DirectionalLight {
+        direction: Vector3::new(0.0, 0.0, -1.0),
+        color: Color {red: 1.0, green: 1.0, blue: 1.0},
+        intensity: 10.0,
+    })];
+    scene.sampler = Box::new(sampler::StratifiedSampler::new(4, 0));
+    scene.adaptive_sampling = Some(AdaptiveSampling { threshold: 0.01, max_samples: 64 });
+
+    let film = render_film(&scene, None);
+    let counts: Vec<u32> = (0..16).flat_map(|y| (0..16).map(move |x| (x, y)))
+        .map(|(x, y)| film.variance(x, y).count())
+        .collect();
+    // Flat pixels stop at the sampler's count, edge pixels carry on as far as they're allowed
+    assert_eq!(film.variance(0, 0).count(), 4);
+    assert_eq!(film.variance(8, 8).count(), 4);
+    assert!(counts.iter().all(|&count| (4..=64).contains(&count)));
+    assert!(counts.contains(&64));
 }
 
 // Here we implement our Ray class
@@ -107,71 +664,446 @@ pub struct Ray {
     pub direction: Vector3<f64>,
 }
 
-// Prime rays are those that come from the camera, traced through the pixel, into the scene
+// Mirror the incident direction about the normal
+pub fn reflect(incident: Vector3<f64>, normal: Vector3<f64>) -> Vector3<f64> {
+    incident - normal * (2.0 * incident.dot(normal))
+}
+
+// Bend the incident direction through a surface with the given refractive index by Snell's law.
+// `normal` points out of the object, so rays travelling along it are leaving the object and
+// refract from `index` back to 1.0. Returns None under total internal reflection.
+pub fn refract(incident: Vector3<f64>, normal: Vector3<f64>, index: f64) -> Option<Vector3<f64>> {
+    let mut ref_n = normal;
+    let mut eta_i = 1.0;
+    let mut eta_t = index;
+    let mut i_dot_n = incident.dot(normal);
+    if i_dot_n < 0.0 {
+        // Entering the object
+        i_dot_n = -i_dot_n;
+    } else {
+        // Leaving the object
+        ref_n = -normal;
+        std::mem::swap(&mut eta_i, &mut eta_t);
+    }
+
+    let eta = eta_i / eta_t;
+    let k = 1.0 - (eta * eta) * (1.0 - i_dot_n * i_dot_n);
+    if k < 0.0 {
+        return None;
+    }
+    Some(((incident + ref_n * i_dot_n) * eta - ref_n * k.sqrt()).normalize())
+}
+
 impl Ray {
-    pub fn create_prime(x: u32, y: u32, scene: &Scene) -> Ray {
-        // Camera origin is (0, 0, 0) and sensors are located -1 z away.
+    // Mirror the incident direction about the normal, starting just off the surface
+    pub fn create_reflection(normal: Vector3<f64>, incident: Vector3<f64>, intersection: Point3<f64>, bias: f64) -> Ray {
+        Ray {
+            origin: intersection + normal * bias,
+            direction: reflect(incident, normal),
+        }
+    }
 
+    // A ray leaving the surface in any direction, starting just off whichever side it leaves from
+    pub fn create_bounce(normal: Vector3<f64>, direction: Vector3<f64>, intersection: Point3<f64>, bias: f64) -> Ray {
+        let side = if direction.dot(normal) < 0.0 { -normal } else { normal };
+        Ray { origin: intersection + side * bias, direction }
+    }
+
+    // Refract the incident direction through the surface, as `refract` does. Returns None under total
+    // internal reflection.
+    pub fn create_transmission(normal: Vector3<f64>, incident: Vector3<f64>, intersection: Point3<f64>, bias: f64, index: f64) -> Option<Ray> {
+        let direction = refract(incident, normal, index)?;
+        // The transmitted ray starts just beyond the surface, on the far side from the incident ray
+        Some(Ray::create_bounce(normal, direction, intersection, bias))
+    }
+}
+
+// Prime rays are those that come from the camera, traced through the pixel, into the scene
+impl Ray {
+    // x and y are positions on the image in pixels, so (0.5, 0.5) is the center of the top left pixel
+    pub fn create_prime(x: f64, y: f64, scene: &Scene, lens_sample: Point2<f64>, eye: Option<Eye>) -> Option<Ray> {
         // This describes how the ray direction is calculated
-        // First the pixel center is calculated as it's starting value + half a pixel
-        // Then it's normalized to the width of the scene
+        // First the position is normalized to the size of the image along that axis
         // Then it's adjusted from coordinates (0..1) to (-1..1) via *2
-        fn sensor(scene: &Scene, v: u32) -> f64 {
-            let pixel_center = v as f64 + 0.5;
-            let normalized_to_width = pixel_center / scene.width as f64;
-            let adjusted_screen_pos = (normalized_to_width * 2.0) - 1.0;
-            adjusted_screen_pos
+        fn sensor(v: f64, size: u32) -> f64 {
+            let normalized_to_size = v / size as f64;
+            (normalized_to_size * 2.0) - 1.0
         }
 
-        assert!(scene.width > scene.height);
-        let fov_adjustment = (scene.fov.to_radians() / 2.0).tan();
-        let aspect_ratio = (scene.width as f64) / (scene.height as f64);
-        let sensor_x =  sensor(scene, x) * fov_adjustment * aspect_ratio;
-        let sensor_y = -sensor(scene, y) * fov_adjustment;  // y is positive in the down direction
+        // The camera decides how big the sensor is from its field of view and the image's aspect ratio
+        let (half_width, half_height) = scene.camera.sensor_size(scene.width, scene.height);
+        let sensor_x =  sensor(x, scene.width) * half_width;
+        let sensor_y = -sensor(y, scene.height) * half_height;  // y is positive in the down direction
 
-        Ray {
-            origin: Point3::new(0.0, 0.0, 0.0),
-            direction: Vector3 {
-                x: sensor_x,
-                y: sensor_y,
-                z: -1.0       // z is -1.0 because all of our prime rays should go forward from the camera
-            }.normalize()
-        }
+        // The camera turns the sensor position into a world space ray, if its projection covers it
+        scene.camera.create_ray(sensor_x, sensor_y, lens_sample, eye)
     }
 }
 
-pub trait Intersectable {
-    fn intersect(&self, ray: &Ray) -> bool;
+// Everything we know about the point where a ray struck an object
+pub struct Intersection<'a> {
+    pub t: f64,                         // distance along the ray to the hit
+    pub point: Point3<f64>,             // world-space hit point
+    pub normal: Vector3<f64>,           // unit surface normal, pointing out of the object
+    pub uv: Point2<f64>,                // surface texture coordinates
+    pub object: &'a dyn Intersectable,  // the object that was hit
+}
+
+pub trait Intersectable: Bounded {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>>;
+    fn material(&self) -> &Material;
+
+    // Does this object block the ray before max_distance? Used for shadow rays, which don't
+    // need the details of the hit, so shapes with a cheaper test should override it.
+    fn occludes(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.intersect(ray).is_some_and(|hit| hit.t < max_distance)
+    }
+
+    // Total surface area. Emissive objects with a finite area can be sampled as area lights.
+    fn area(&self) -> f64 {
+        f64::INFINITY
+    }
+
+    // A point on the surface, chosen uniformly by area from a sample in the unit square
+    fn sample_surface(&self, _sample: Point2<f64>) -> Option<SurfaceSample> {
+        None
+    }
+}
+
+// A point picked on an object's surface and the outward normal there
+pub struct SurfaceSample {
+    pub point: Point3<f64>,
+    pub normal: Vector3<f64>,
 }
 
 impl Intersectable for Sphere {
-    fn intersect(&self, ray: &Ray) -> bool {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
         // Create a line segment between the ray origin and the center of the sphere
         let l: Vector3<f64> = self.center - ray.origin;
         // Use l as a hypotenuse and find the length of the adjacent side
-        let adj2 = l.dot(ray.direction);
+        let adj = l.dot(ray.direction);
         // Find the length-squared of the opposite side
-        // This is equivalent to (but faster than) (l.length() * l.length()) - (adj2 * adj2)
-        let d2 = l.dot(l) - (adj2 * adj2);
-        // If that length-squared is less than radius squared, the ray intersects the sphere
-        d2 < (self.radius * self.radius)
+        // This is equivalent to (but faster than) (l.length() * l.length()) - (adj * adj)
+        let d2 = l.dot(l) - (adj * adj);
+        // If that length-squared is greater than radius squared, the ray misses the sphere
+        let radius2 = self.radius * self.radius;
+        if d2 > radius2 {
+            return None;
+        }
+        // The two roots of the quadratic sit half a chord either side of the adjacent point
+        let half_chord = (radius2 - d2).sqrt();
+        let t0 = adj - half_chord;
+        let t1 = adj + half_chord;
+        // Both hits behind the origin means the sphere is behind the ray
+        if t1 < 0.0 {
+            return None;
+        }
+        // If the near hit is behind the origin, the ray starts inside the sphere and exits at the far hit
+        let t = if t0 < 0.0 { t1 } else { t0 };
+
+        let point = ray.origin + ray.direction * t;
+        let normal = (point - self.center).normalize();
+        // Wrap u around the equator and run v from the south pole up to the north pole
+        let uv = Point2::new(
+            (1.0 + normal.z.atan2(normal.x) / PI) * 0.5,
+            1.0 - normal.y.acos() / PI,
+        );
+        Some(Intersection {
+            t,
+            point,
+            normal,
+            uv,
+            object: self,
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, sample: Point2<f64>) -> Option<SurfaceSample> {
+        // Uniform in height and angle around the axis, which is uniform by area on a sphere
+        let z = 1.0 - 2.0 * sample.x;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * sample.y;
+        let normal = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+        Some(SurfaceSample { point: self.center + normal * self.radius, normal })
+    }
+}
+
+impl Bounded for Sphere {
+    fn bounding_box(&self) -> Aabb {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        Aabb { min: self.center - r, max: self.center + r }
+    }
+}
+
+// Build two unit vectors perpendicular to the unit vector `n` and to each other
+// REF: Duff et al., "Building an Orthonormal Basis, Revisited", JCGT 2017
+pub fn orthonormal_basis(n: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let sign = 1.0f64.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vector3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vector3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+// Distance along the ray to the plane through `point` with unit `normal`, if it lies in front of the ray
+fn intersect_plane(ray: &Ray, point: Point3<f64>, normal: Vector3<f64>, one_sided: bool) -> Option<f64> {
+    let denom = normal.dot(ray.direction);
+    // Rays parallel to the plane never hit it, and one-sided planes are invisible from behind
+    if denom.abs() < 1e-9 || (one_sided && denom > 0.0) {
+        return None;
+    }
+    let t = (point - ray.origin).dot(normal) / denom;
+    if t >= 0.0 { Some(t) } else { None }
+}
+
+impl Intersectable for Plane {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let normal = self.normal.normalize();
+        let t = intersect_plane(ray, self.point, normal, self.one_sided)?;
+        let point = ray.origin + ray.direction * t;
+        // Texture coordinates are distances in world units along the plane's tangents, so textures tile
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        let offset = point - self.point;
+        Some(Intersection {
+            t,
+            point,
+            normal,
+            uv: Point2::new(offset.dot(tangent), offset.dot(bitangent)),
+            object: self,
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+impl Bounded for Plane {
+    fn bounding_box(&self) -> Aabb {
+        Aabb::infinite()
+    }
+}
+
+impl Bounded for Disk {
+    fn bounding_box(&self) -> Aabb {
+        // Along each axis the rim reaches radius * sin(angle between the axis and the normal)
+        let n = self.normal.normalize();
+        let extent = Vector3::new(
+            self.radius * (1.0 - n.x * n.x).max(0.0).sqrt(),
+            self.radius * (1.0 - n.y * n.y).max(0.0).sqrt(),
+            self.radius * (1.0 - n.z * n.z).max(0.0).sqrt(),
+        );
+        Aabb { min: self.center - extent, max: self.center + extent }
+    }
+}
+
+impl Intersectable for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let normal = self.normal.normalize();
+        let t = intersect_plane(ray, self.center, normal, false)?;
+        let point = ray.origin + ray.direction * t;
+        let offset = point - self.center;
+        if offset.magnitude2() > self.radius * self.radius {
+            return None;
+        }
+        // Texture coordinates map the disk's bounding square onto (0..1, 0..1)
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        Some(Intersection {
+            t,
+            point,
+            normal,
+            uv: Point2::new(
+                (offset.dot(tangent) / self.radius + 1.0) * 0.5,
+                (offset.dot(bitangent) / self.radius + 1.0) * 0.5,
+            ),
+            object: self,
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, sample: Point2<f64>) -> Option<SurfaceSample> {
+        // The square root spreads the samples evenly by area rather than bunching them at the center
+        let normal = self.normal.normalize();
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        let r = self.radius * sample.x.sqrt();
+        let phi = 2.0 * PI * sample.y;
+        Some(SurfaceSample { point: self.center + (tangent * phi.cos() + bitangent * phi.sin()) * r, normal })
     }
 }
 
+#[test]
+fn test_sphere_intersection() {
+    let sphere = Sphere {
+        center: Point3 {x: 0.0, y: 0.0, z: -5.0},
+        radius: 1.0,
+        material: Material::matte(Color {red: 0.4, green: 1.0, blue: 0.4})
+    };
+
+    // From outside, the near side of the sphere is hit
+    let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    let hit = sphere.intersect(&ray).unwrap();
+    assert!((hit.t - 4.0).abs() < 1e-9);
+    assert!((hit.normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+
+    // From inside, the far side is hit
+    let ray = Ray { origin: Point3::new(0.0, 0.0, -5.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    let hit = sphere.intersect(&ray).unwrap();
+    assert!((hit.t - 1.0).abs() < 1e-9);
+    assert!((hit.point - Point3::new(0.0, 0.0, -6.0)).magnitude() < 1e-9);
+
+    // Facing away, nothing is hit
+    let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, 1.0) };
+    assert!(sphere.intersect(&ray).is_none());
+}
+
+#[test]
+fn test_plane_and_disk_intersection() {
+    let plane = Plane {
+        point: Point3::new(0.0, -2.0, 0.0),
+        normal: Vector3::new(0.0, 1.0, 0.0),
+        one_sided: true,
+        material: Material::matte(Color {red: 0.5, green: 0.5, blue: 0.5})
+    };
+    let down = Ray { origin: Point3::new(1.0, 0.0, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+    let up = Ray { origin: Point3::new(1.0, -4.0, 0.0), direction: Vector3::new(0.0, 1.0, 0.0) };
+    let hit = plane.intersect(&down).unwrap();
+    assert!((hit.t - 2.0).abs() < 1e-9);
+    assert!((hit.normal - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+    // One-sided planes can't be seen from below
+    assert!(plane.intersect(&up).is_none());
+
+    let disk = Disk {
+        center: Point3::new(0.0, -2.0, 0.0),
+        normal: Vector3::new(0.0, 1.0, 0.0),
+        radius: 1.5,
+        material: Material::matte(Color {red: 0.5, green: 0.5, blue: 0.5})
+    };
+    let hit = disk.intersect(&up).unwrap();
+    assert!((hit.t - 2.0).abs() < 1e-9);
+    assert!(hit.uv.x >= 0.0 && hit.uv.x <= 1.0 && hit.uv.y >= 0.0 && hit.uv.y <= 1.0);
+    let outside = Ray { origin: Point3::new(2.0, 0.0, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+    assert!(disk.intersect(&outside).is_none());
+}
+
 fn main() { 
+    let mut objects: Vec<Box<dyn Intersectable>> = vec![
+        Box::new(Sphere {
+            center: Point3 {x: 0.0, y: 0.0, z: -5.0},
+            radius: 1.0,
+            material: Material::matte(Color {red: 0.4, green: 1.0, blue: 0.4})
+        }),
+        Box::new(Sphere {
+            center: Point3 {x: -3.0, y: 1.0, z: -6.0},
+            radius: 2.0,
+            material: Material::matte(Color {red: 1.0, green: 0.2, blue: 0.2})
+        }),
+        Box::new(Sphere {
+            center: Point3 {x: 2.0, y: 1.0, z: -4.0},
+            radius: 1.5,
+            material: Material::reflective(Color {red: 0.2, green: 0.2, blue: 1.0}, 0.6)
+        }),
+        Box::new(Sphere {
+            center: Point3 {x: 0.5, y: -1.0, z: -3.0},
+            radius: 0.8,
+            material: Material::transparent(1.5)
+        }),
+        Box::new(Plane {
+            point: Point3 {x: 0.0, y: -2.0, z: 0.0},
+            normal: Vector3 {x: 0.0, y: 1.0, z: 0.0},
+            one_sided: false,
+            material: Material::reflective(Color {red: 0.6, green: 0.6, blue: 0.6}, 0.2)
+        }),
+        Box::new(Disk {
+            center: Point3 {x: 0.0, y: 0.0, z: -12.0},
+            normal: Vector3 {x: 0.0, y: 0.0, z: 1.0},
+            radius: 4.0,
+            material: Material::matte(Color {red: 1.0, green: 0.8, blue: 0.3})
+        }),
+    ];
+
+    // Any OBJ files named on the command line are added to the scene
+    for path in std::env::args().skip(1) {
+        let meshes = match obj::load_obj(std::path::Path::new(&path)) {
+            Ok((meshes, warnings)) => {
+                for warning in warnings {
+                    eprintln!("warning: {}", warning);
+                }
+                meshes
+            }
+            Err(err) => {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        };
+        objects.extend(meshes.into_iter().map(|mesh| Box::new(mesh) as Box<dyn Intersectable>));
+    }
+
     let scene = Scene {
         width: 800,
         height: 600,
-        fov: 90.0,
-        sphere: Sphere {
-            center: Point3 {x: 0.0, y: 0.0, z: -5.0},
-            radius: 1.0,
-            color: Color {red: 0.4, green: 1.0, blue: 0.4}
-        }
+        camera: Camera {
+            position: Point3 {x: 0.0, y: 1.0, z: 2.0},
+            look_at: Point3 {x: 0.0, y: -0.5, z: -5.0},
+            up: Vector3 {x: 0.0, y: 1.0, z: 0.0},
+            fov: 90.0,
+            fov_axis: FovAxis::Vertical,
+            projection: Projection::Perspective,
+            aperture: 0.05,
+            focus_distance: 6.0,
+            blades: 6,
+            stereo: None
+        },
+        objects: Aggregate::new(objects),
+        lights: vec![
+            Light::Directional(DirectionalLight {
+                direction: Vector3 {x: -0.5, y: -1.0, z: -0.5},
+                color: Color {red: 1.0, green: 1.0, blue: 1.0},
+                intensity: 2.0
+            }),
+            Light::Point(PointLight {
+                position: Point3 {x: 3.0, y: 3.0, z: -2.0},
+                color: Color {red: 1.0, green: 0.9, blue: 0.8},
+                intensity: 400.0
+            }),
+            Light::Spot(SpotLight {
+                position: Point3 {x: -3.0, y: 6.0, z: -9.0},
+                direction: Vector3 {x: 0.0, y: -1.0, z: 0.0},
+                angle: 30.0,
+                penumbra: 10.0,
+                color: Color {red: 0.6, green: 0.8, blue: 1.0},
+                intensity: 1500.0
+            }),
+        ],
+        shadow_bias: 1e-4,
+        max_recursion_depth: 5,
+        sampler: Box::new(SobolSampler::new(16, 0)),
+        adaptive_sampling: Some(AdaptiveSampling { threshold: 0.05, max_samples: 64 }),
+        filter: Box::new(MitchellFilter { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+        integrator: Box::new(PathTracer { roulette_depth: 3 })
     };
 
-    let img: DynamicImage = render(&scene);
-
-    img.save("image.png");
+    match render(&scene) {
+        Rendered::Image(img) => img.save("image.png").expect("failed to save image.png"),
+        Rendered::Eyes(left, right) => {
+            left.save("image_left.png").expect("failed to save image_left.png");
+            right.save("image_right.png").expect("failed to save image_right.png");
+        }
+    }
 
 }
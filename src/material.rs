@@ -0,0 +1,81 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use cgmath::Point2;
+use image::{DynamicImage, GenericImageView, ImageResult};
+
+use crate::Color;
+
+// An image mapped onto a surface through its texture coordinates
+pub struct Texture {
+    pub image: DynamicImage,
+}
+
+impl Texture {
+    pub fn open(path: &Path) -> ImageResult<Texture> {
+        Ok(Texture { image: image::open(path)? })
+    }
+
+    // Look up the texel under `uv`. v runs up the image and both coordinates wrap, so textures tile.
+    pub fn sample(&self, uv: Point2<f64>) -> Color {
+        let (width, height) = self.image.dimensions();
+        if width == 0 || height == 0 {
+            return Color::black();
+        }
+        let x = (uv.x.rem_euclid(1.0) * width as f64) as u32;
+        let y = ((1.0 - uv.y.rem_euclid(1.0)) * height as f64) as u32;
+        Color::from_rgba(self.image.get_pixel(x.min(width - 1), y.min(height - 1)))
+    }
+}
+
+// How a surface responds to light. Field names follow the Wavefront MTL statements they're loaded from.
+#[derive(Clone)]
+pub struct Material {
+    pub color: Color,               // Kd: diffuse albedo
+    pub specular: Color,            // Ks: specular albedo
+    pub shininess: f32,             // Ns: specular exponent
+    pub opacity: f32,               // d: 1.0 is fully opaque
+    pub refractive_index: f64,      // Ni
+    pub texture: Option<Arc<Texture>>,  // map_Kd: replaces `color` where present, shared between meshes
+    pub reflectivity: f32,          // fraction of light mirrored rather than diffusely reflected
+    pub emission: Color,            // Ke: light given off from the front of the surface
+}
+
+impl Material {
+    // A plain diffuse surface of a single color
+    pub fn matte(color: Color) -> Material {
+        Material {
+            color,
+            specular: Color {red: 0.0, green: 0.0, blue: 0.0},
+            shininess: 0.0,
+            opacity: 1.0,
+            refractive_index: 1.0,
+            texture: None,
+            reflectivity: 0.0,
+            emission: Color::black(),
+        }
+    }
+
+    // A surface that mirrors `reflectivity` of the light reaching it and diffusely reflects the rest
+    pub fn reflective(color: Color, reflectivity: f32) -> Material {
+        Material { reflectivity, ..Material::matte(color) }
+    }
+
+    // A clear dielectric such as glass (1.5) or water (1.33), which reflects and refracts all its light
+    pub fn transparent(refractive_index: f64) -> Material {
+        Material { opacity: 0.0, refractive_index, ..Material::matte(Color::black()) }
+    }
+
+    // A surface that gives off light of `emission` and reflects none
+    pub fn emissive(emission: Color) -> Material {
+        Material { emission, ..Material::matte(Color::black()) }
+    }
+
+    // The diffuse color at the given texture coordinates
+    pub fn color_at(&self, uv: Point2<f64>) -> Color {
+        match &self.texture {
+            Some(texture) => texture.sample(uv),
+            None => self.color,
+        }
+    }
+}
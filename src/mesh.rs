@@ -0,0 +1,313 @@
+use cgmath::{Point2, Point3, Vector3, InnerSpace, EuclideanSpace};
+
+use crate::{Intersectable, Intersection, Ray, SurfaceSample};
+use crate::bvh::{Aabb, Bounded, Bvh};
+use crate::material::Material;
+
+// A single free-standing triangle. Vertices wind counter-clockwise around the front face.
+pub struct Triangle {
+    pub vertices: [Point3<f64>; 3],
+    pub material: Material
+}
+
+// A triangle mesh. Every vertex has a position and, optionally, a normal and texture coordinates;
+// triangles are triples of indices into those shared buffers. The BVH over the triangles is built
+// once in `Mesh::new`, so the geometry is read-only afterwards.
+pub struct Mesh {
+    positions: Vec<Point3<f64>>,
+    normals: Vec<Vector3<f64>>,     // one per vertex, or empty for flat shading
+    uvs: Vec<Point2<f64>>,          // one per vertex, or empty
+    triangles: Vec<[usize; 3]>,
+    area_cdf: Vec<f64>,             // running total of the triangles' areas, for sampling points by area
+    bvh: Bvh,
+    pub material: Material
+}
+
+// Where a ray hit a triangle: the distance along the ray and the barycentric weight of each vertex
+struct TriangleHit {
+    t: f64,
+    barycentric: [f64; 3],
+}
+
+// Watertight ray/triangle intersection, so rays through a shared edge can't slip between two triangles
+// REF: Woop, Benthin and Wald, "Watertight Ray/Triangle Intersection", JCGT 2013
+fn intersect_triangle(ray: &Ray, p0: Point3<f64>, p1: Point3<f64>, p2: Point3<f64>) -> Option<TriangleHit> {
+    let dir = ray.direction;
+
+    // Pick the dominant axis of the ray as z, keeping the winding of the other two axes
+    let kz = if dir.x.abs() > dir.y.abs() {
+        if dir.x.abs() > dir.z.abs() { 0 } else { 2 }
+    } else if dir.y.abs() > dir.z.abs() { 1 } else { 2 };
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear and scale so the ray runs down +z from the origin
+    let sx = dir[kx] / dir[kz];
+    let sy = dir[ky] / dir[kz];
+    let sz = 1.0 / dir[kz];
+
+    let a = p0 - ray.origin;
+    let b = p1 - ray.origin;
+    let c = p2 - ray.origin;
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+
+    // Scaled barycentric coordinates are the signed areas of the edges seen from the ray
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    // Scaled hit distance, which must lie in front of the ray
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+    if t <= 0.0 {
+        return None;
+    }
+    Some(TriangleHit { t, barycentric: [u / det, v / det, w / det] })
+}
+
+fn face_normal(p0: Point3<f64>, p1: Point3<f64>, p2: Point3<f64>) -> Vector3<f64> {
+    (p1 - p0).cross(p2 - p0).normalize()
+}
+
+fn triangle_area(p0: Point3<f64>, p1: Point3<f64>, p2: Point3<f64>) -> f64 {
+    (p1 - p0).cross(p2 - p0).magnitude() * 0.5
+}
+
+// A point uniformly distributed over the triangle, as barycentric weights
+// REF: Pharr, Jakob and Humphreys, "Physically Based Rendering", 3rd ed., section 13.6.5
+fn sample_barycentric(sample: Point2<f64>) -> [f64; 3] {
+    let root = sample.x.sqrt();
+    let b1 = sample.y * root;
+    [1.0 - root, b1, root - b1]
+}
+
+fn barycentric_point(p0: Point3<f64>, p1: Point3<f64>, p2: Point3<f64>, [b0, b1, b2]: [f64; 3]) -> Point3<f64> {
+    Point3::from_vec(p0.to_vec() * b0 + p1.to_vec() * b1 + p2.to_vec() * b2)
+}
+
+impl Bounded for Triangle {
+    fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(self.vertices.iter().cloned())
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let [p0, p1, p2] = self.vertices;
+        let hit = intersect_triangle(ray, p0, p1, p2)?;
+        let [_, b1, b2] = hit.barycentric;
+        Some(Intersection {
+            t: hit.t,
+            point: ray.origin + ray.direction * hit.t,
+            normal: face_normal(p0, p1, p2),
+            uv: Point2::new(b1, b2),
+            object: self,
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn area(&self) -> f64 {
+        let [p0, p1, p2] = self.vertices;
+        triangle_area(p0, p1, p2)
+    }
+
+    fn sample_surface(&self, sample: Point2<f64>) -> Option<SurfaceSample> {
+        let [p0, p1, p2] = self.vertices;
+        Some(SurfaceSample {
+            point: barycentric_point(p0, p1, p2, sample_barycentric(sample)),
+            normal: face_normal(p0, p1, p2),
+        })
+    }
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Point3<f64>>,
+        normals: Vec<Vector3<f64>>,
+        uvs: Vec<Point2<f64>>,
+        triangles: Vec<[usize; 3]>,
+        material: Material,
+    ) -> Mesh {
+        assert!(normals.is_empty() || normals.len() == positions.len(), "need one normal per vertex");
+        assert!(uvs.is_empty() || uvs.len() == positions.len(), "need one uv per vertex");
+        assert!(
+            triangles.iter().flatten().all(|&i| i < positions.len()),
+            "triangle index out of bounds"
+        );
+        let bounds: Vec<Aabb> = triangles.iter()
+            .map(|triangle| Aabb::from_points(triangle.iter().map(|&i| positions[i])))
+            .collect();
+        let bvh = Bvh::new(&bounds);
+        let area_cdf = triangles.iter()
+            .scan(0.0, |total, &[i0, i1, i2]| {
+                *total += triangle_area(positions[i0], positions[i1], positions[i2]);
+                Some(*total)
+            })
+            .collect();
+        Mesh { positions, normals, uvs, triangles, area_cdf, bvh, material }
+    }
+
+    pub fn positions(&self) -> &[Point3<f64>] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> &[Vector3<f64>] {
+        &self.normals
+    }
+
+    pub fn uvs(&self) -> &[Point2<f64>] {
+        &self.uvs
+    }
+
+    pub fn triangles(&self) -> &[[usize; 3]] {
+        &self.triangles
+    }
+
+    // Build the intersection for a hit on one of the mesh's triangles, interpolating vertex attributes
+    fn shade(&self, ray: &Ray, triangle: &[usize; 3], hit: TriangleHit) -> Intersection<'_> {
+        let [i0, i1, i2] = *triangle;
+        let [b0, b1, b2] = hit.barycentric;
+
+        // Smooth shading blends the vertex normals; without them, fall back to the flat face normal
+        let normal = if self.normals.is_empty() {
+            face_normal(self.positions[i0], self.positions[i1], self.positions[i2])
+        } else {
+            (self.normals[i0] * b0 + self.normals[i1] * b1 + self.normals[i2] * b2).normalize()
+        };
+        let uv = if self.uvs.is_empty() {
+            Point2::new(b1, b2)
+        } else {
+            Point2::from_vec(self.uvs[i0].to_vec() * b0 + self.uvs[i1].to_vec() * b1 + self.uvs[i2].to_vec() * b2)
+        };
+
+        Intersection {
+            t: hit.t,
+            point: ray.origin + ray.direction * hit.t,
+            normal,
+            uv,
+            object: self,
+        }
+    }
+}
+
+impl Bounded for Mesh {
+    fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(self.positions.iter().cloned())
+    }
+}
+
+impl Intersectable for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let mut nearest: Option<(&[usize; 3], TriangleHit)> = None;
+        self.bvh.traverse(ray, f64::INFINITY, |i, t_max| {
+            let triangle = &self.triangles[i];
+            let [i0, i1, i2] = *triangle;
+            let hit = intersect_triangle(ray, self.positions[i0], self.positions[i1], self.positions[i2])
+                .filter(|hit| hit.t < t_max)?;
+            let t = hit.t;
+            nearest = Some((triangle, hit));
+            Some(t)
+        });
+        nearest.map(|(triangle, hit)| self.shade(ray, triangle, hit))
+    }
+
+    fn occludes(&self, ray: &Ray, max_distance: f64) -> bool {
+        // Shadow rays only need to know a triangle is in the way, not which one or where
+        self.bvh.any_hit(ray, max_distance, |i, t_max| {
+            let [i0, i1, i2] = self.triangles[i];
+            intersect_triangle(ray, self.positions[i0], self.positions[i1], self.positions[i2])
+                .is_some_and(|hit| hit.t < t_max)
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn area(&self) -> f64 {
+        self.area_cdf.last().cloned().unwrap_or(0.0)
+    }
+
+    fn sample_surface(&self, sample: Point2<f64>) -> Option<SurfaceSample> {
+        // Pick a triangle in proportion to its area, then reuse what's left of the sample within it
+        let total = self.area();
+        if total <= 0.0 {
+            return None;
+        }
+        let target = sample.x * total;
+        let i = self.area_cdf.partition_point(|&cdf| cdf <= target).min(self.triangles.len() - 1);
+        let start = if i == 0 { 0.0 } else { self.area_cdf[i - 1] };
+        let remapped = ((target - start) / (self.area_cdf[i] - start)).clamp(0.0, 1.0);
+
+        let [i0, i1, i2] = self.triangles[i];
+        let (p0, p1, p2) = (self.positions[i0], self.positions[i1], self.positions[i2]);
+        Some(SurfaceSample {
+            point: barycentric_point(p0, p1, p2, sample_barycentric(Point2::new(remapped, sample.y))),
+            normal: face_normal(p0, p1, p2),
+        })
+    }
+}
+
+#[test]
+fn test_triangle_intersection() {
+    use crate::Color;
+
+    let triangle = Triangle {
+        vertices: [Point3::new(-1.0, -1.0, -3.0), Point3::new(1.0, -1.0, -3.0), Point3::new(0.0, 1.0, -3.0)],
+        material: Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0})
+    };
+    let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    let hit = triangle.intersect(&ray).unwrap();
+    assert!((hit.t - 3.0).abs() < 1e-9);
+    assert!((hit.normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+
+    let miss = Ray { origin: Point3::new(2.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    assert!(triangle.intersect(&miss).is_none());
+}
+
+#[test]
+fn test_mesh_is_watertight_and_smooth() {
+    use crate::Color;
+
+    // A unit quad split along its diagonal, with normals tilted left at x=-1 and right at x=1
+    let mesh = Mesh::new(
+        vec![
+            Point3::new(-1.0, -1.0, -2.0),
+            Point3::new(1.0, -1.0, -2.0),
+            Point3::new(1.0, 1.0, -2.0),
+            Point3::new(-1.0, 1.0, -2.0),
+        ],
+        vec![
+            Vector3::new(-1.0, 0.0, 1.0).normalize(),
+            Vector3::new(1.0, 0.0, 1.0).normalize(),
+            Vector3::new(1.0, 0.0, 1.0).normalize(),
+            Vector3::new(-1.0, 0.0, 1.0).normalize(),
+        ],
+        vec![],
+        vec![[0, 1, 2], [0, 2, 3]],
+        Material::matte(Color {red: 1.0, green: 1.0, blue: 1.0}),
+    );
+
+    // Straight through the shared diagonal edge, where the normals average out to face the camera
+    let ray = Ray { origin: Point3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    let hit = mesh.intersect(&ray).unwrap();
+    assert!((hit.t - 2.0).abs() < 1e-9);
+    assert!((hit.normal - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+
+    // Towards the right-hand edge the interpolated normal leans right
+    let ray = Ray { origin: Point3::new(0.5, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+    assert!(mesh.intersect(&ray).unwrap().normal.x > 0.0);
+}
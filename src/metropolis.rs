@@ -0,0 +1,284 @@
+// Primary sample space Metropolis light transport. A path tracer turns a list of random numbers in
+// [0, 1) into a path through a pixel, and the light it carries. Rather than drawing fresh numbers for
+// every path, this keeps a Markov chain of them, mostly nudging the numbers a little and keeping the
+// result with a probability that makes the chain visit paths in proportion to how bright they are.
+// Once it finds a hard-to-reach path that carries a lot of light, such as one squeezing through a
+// crack under a door, it explores the paths near it instead of losing it again.
+//
+// Small steps nudge every number a little; large steps replace them all, so the chain never gets stuck
+// in one bright region. The chain only gets the relative brightness of the image right, so a bootstrap
+// phase of ordinary paths first measures its overall brightness, and picks where the chain starts.
+// REF: Kelemen, Szirmay-Kalos, Antal and Csonka, "A Simple and Robust Mutation Strategy for the
+// Metropolis Light Transport Algorithm", Eurographics 2002
+// REF: Pharr, Jakob and Humphreys, "Physically Based Rendering", 3rd ed., section 16.4.4
+
+use cgmath::Point2;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+use crate::{Color, Ray, Scene};
+use crate::camera::Eye;
+use crate::integrator::{Integrator, RenderPass};
+use crate::sampler::{hash, Sampler};
+
+// A point in primary sample space: the random numbers one path is made from, handed out in order like
+// any other sampler's. Numbers past the end of the list are drawn fresh as the path asks for them.
+#[derive(Clone)]
+pub struct PrimarySample {
+    values: Vec<f64>,
+    index: usize,
+    rng: SmallRng,
+}
+
+impl PrimarySample {
+    pub fn new(seed: u64) -> PrimarySample {
+        PrimarySample { values: vec![], index: 0, rng: SmallRng::seed_from_u64(seed) }
+    }
+
+    // A large step forgets every number, so each is drawn afresh
+    pub fn large_step(&mut self) {
+        self.values.clear();
+    }
+
+    // A small step moves each number by up to 1/64, wrapping around, usually by much less
+    pub fn small_step(&mut self) {
+        const S1: f64 = 1.0 / 1024.0;
+        const S2: f64 = 1.0 / 64.0;
+        for value in &mut self.values {
+            let offset = S2 * (-(S2 / S1).ln() * self.rng.gen::<f64>()).exp();
+            let moved = if self.rng.gen::<bool>() { *value + offset } else { *value - offset };
+            *value = moved - moved.floor();
+        }
+    }
+}
+
+impl Sampler for PrimarySample {
+    fn samples_per_pixel(&self) -> u32 {
+        1
+    }
+
+    // The pixel is one of the numbers, so every path starts at the same place in the list
+    fn start_pixel_sample(&mut self, _pixel: (u32, u32), _index: u32) {
+        self.index = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        if self.index == self.values.len() {
+            let value = self.rng.gen();
+            self.values.push(value);
+        }
+        self.index += 1;
+        self.values[self.index - 1]
+    }
+
+    fn get_2d(&mut self) -> Point2<f64> {
+        Point2::new(self.get_1d(), self.get_1d())
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// Metropolis sampling of the paths `integrator` makes from its random numbers, normally a path tracer.
+// Every camera sample the film takes runs `mutations_per_sample` steps of a Markov chain, splatting
+// light wherever on the image the chain goes, so the film's samples per pixel set how long it runs.
+// Each render starts its own chain, from a bootstrap phase of `bootstrap_samples` ordinary paths, and
+// each eye of a stereo camera gets its own.
+pub struct MetropolisIntegrator {
+    pub integrator: Box<dyn Integrator>,
+    pub bootstrap_samples: u32,
+    pub mutations_per_sample: u32,
+    pub large_step_probability: f64,
+    pub seed: u64,
+}
+
+// Where one render's chain is: its current path's numbers, and the pixel and light they give
+struct Chain<'a> {
+    metropolis: &'a MetropolisIntegrator,
+    eye: Option<Eye>,
+    rng: SmallRng,
+    current: PrimarySample,
+    raster: Point2<f64>,
+    radiance: Color,
+    brightness: f64,        // the image's average luminance, from the bootstrap phase
+}
+
+impl MetropolisIntegrator {
+    pub fn new(integrator: Box<dyn Integrator>, bootstrap_samples: u32, mutations_per_sample: u32) -> MetropolisIntegrator {
+        MetropolisIntegrator {
+            integrator,
+            bootstrap_samples: bootstrap_samples.max(1),
+            mutations_per_sample: mutations_per_sample.max(1),
+            large_step_probability: 0.3,
+            seed: 0,
+        }
+    }
+
+    // The pixel position and light of the path made from `sample`'s numbers, seen from `eye`: the first two
+    // pick a point on the image and the next two a point on the lens, as when rendering normally
+    fn evaluate(&self, scene: &Scene, eye: Option<Eye>, sample: &mut PrimarySample) -> (Point2<f64>, Color) {
+        sample.start_pixel_sample((0, 0), 0);
+        let offset = sample.get_2d();
+        let raster = Point2::new(offset.x * scene.width as f64, offset.y * scene.height as f64);
+        let lens_sample = sample.get_2d();
+        let radiance = match Ray::create_prime(raster.x, raster.y, scene, lens_sample, eye) {
+            Some(ray) => self.integrator.radiance(scene, &ray, sample),
+            None => Color::black(),
+        };
+        (raster, radiance)
+    }
+
+    // Trace the bootstrap paths, and start the chain from one of them picked in proportion to its brightness
+    fn bootstrap(&self, scene: &Scene, eye: Option<Eye>) -> Chain<'_> {
+        let mut rng = SmallRng::seed_from_u64(hash(&[self.seed]));
+        let seed = |i: u32| hash(&[self.seed, i as u64]);
+        let luminances: Vec<f64> = (0..self.bootstrap_samples)
+            .map(|i| self.evaluate(scene, eye, &mut PrimarySample::new(seed(i))).1.luminance() as f64)
+            .collect();
+        let total: f64 = luminances.iter().sum();
+
+        let mut chosen = 0;
+        let mut target = rng.gen::<f64>() * total;
+        for (i, &luminance) in luminances.iter().enumerate() {
+            chosen = i as u32;
+            if target < luminance {
+                break;
+            }
+            target -= luminance;
+        }
+        // Replaying the chosen path's seed gives back its numbers
+        let mut current = PrimarySample::new(seed(chosen));
+        let (raster, radiance) = self.evaluate(scene, eye, &mut current);
+        let brightness = total / self.bootstrap_samples as f64;
+        Chain { metropolis: self, eye, rng, current, raster, radiance, brightness }
+    }
+}
+
+impl Integrator for MetropolisIntegrator {
+    // Without a film to splat onto, there's nowhere for the chain's light to go, so trace the wrapped
+    // integrator's path for the ray as usual
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        self.integrator.radiance(scene, ray, sampler)
+    }
+
+    fn begin_pass<'a>(&'a self, scene: &'a Scene, eye: Option<Eye>, _pass: u32) -> Option<Box<dyn RenderPass + 'a>> {
+        Some(Box::new(self.bootstrap(scene, eye)))
+    }
+}
+
+impl RenderPass for Chain<'_> {
+    fn radiance_and_splats(
+        &mut self,
+        scene: &Scene,
+        _ray: &Ray,
+        _sampler: &mut dyn Sampler,
+        splat: &mut dyn FnMut(Point2<f64>, Color),
+    ) -> Color {
+        // A black image has nothing for the chain to find
+        if self.brightness == 0.0 {
+            return Color::black();
+        }
+        let metropolis = self.metropolis;
+        // Each path stands for the image's brightness spread over the paths the chain visits, shared
+        // between the current path and the proposed one by how likely the chain is to move
+        let scale = self.brightness / metropolis.mutations_per_sample as f64;
+
+        for _ in 0..metropolis.mutations_per_sample {
+            let mut proposed = self.current.clone();
+            proposed.rng = SmallRng::seed_from_u64(self.rng.gen());
+            if self.rng.gen::<f64>() < metropolis.large_step_probability {
+                proposed.large_step();
+            } else {
+                proposed.small_step();
+            }
+            let (raster, radiance) = metropolis.evaluate(scene, self.eye, &mut proposed);
+
+            let luminance = radiance.luminance() as f64;
+            let current_luminance = self.radiance.luminance() as f64;
+            let acceptance = if current_luminance > 0.0 { (luminance / current_luminance).min(1.0) } else { 1.0 };
+            // Splat both paths by their expected share rather than only the one the chain ends on
+            if luminance > 0.0 {
+                splat(raster, radiance * (acceptance * scale / luminance) as f32);
+            }
+            if current_luminance > 0.0 {
+                splat(self.raster, self.radiance * ((1.0 - acceptance) * scale / current_luminance) as f32);
+            }
+            if self.rng.gen::<f64>() < acceptance {
+                self.current = proposed;
+                self.raster = raster;
+                self.radiance = radiance;
+            }
+        }
+        Color::black()
+    }
+}
+
+#[test]
+fn test_metropolis() {
+    use cgmath::Point3;
+    use crate::{lamp_scene, render_film, Sphere};
+    use crate::camera::{Camera, Stereo, StereoLayout};
+    use crate::material::Material;
+    use crate::path_tracer::PathTracer;
+    use crate::sampler::SobolSampler;
+
+    // Small steps stay close and within [0, 1), and the numbers are the same when read again
+    let mut sample = PrimarySample::new(3);
+    let first: Vec<f64> = (0..8).map(|_| sample.get_1d()).collect();
+    sample.start_pixel_sample((0, 0), 0);
+    assert_eq!(sample.get_1d(), first[0]);
+    sample.small_step();
+    sample.start_pixel_sample((0, 0), 0);
+    for &value in &first {
+        let moved = sample.get_1d();
+        let distance = (moved - value).abs();
+        assert!((0.0..1.0).contains(&moved) && distance.min(1.0 - distance) <= 1.0 / 64.0);
+    }
+
+    // The lamp scene with a ball beside the lamp, seen from the front
+    let mut scene = lamp_scene(vec![
+        Box::new(Sphere {
+            center: Point3::new(1.5, 0.5, 0.0),
+            radius: 0.5,
+            material: Material::matte(Color {red: 0.5, green: 0.5, blue: 0.5}),
+        }),
+    ]);
+    scene.width = 8;
+    scene.height = 6;
+    scene.camera = Camera { position: Point3::new(0.0, 0.5, 3.0), look_at: Point3::new(0.0, 0.3, 0.0), ..Camera::default() };
+    scene.max_recursion_depth = 3;
+    scene.sampler = Box::new(SobolSampler::new(64, 0));
+    scene.integrator = Box::new(PathTracer { roulette_depth: 8 });
+
+    // The chain's image agrees with path tracing overall, and roughly in its brightest pixels
+    let path_traced = render_film(&scene, None);
+    let path_tracer = Box::new(PathTracer { roulette_depth: 8 });
+    scene.integrator = Box::new(MetropolisIntegrator::new(path_tracer, 400000, 32));
+    let metropolis = render_film(&scene, None);
+    let (mut traced_total, mut metropolis_total) = (0.0, 0.0);
+    for y in 0..scene.height {
+        for x in 0..scene.width {
+            let (traced, sampled) = (path_traced.get_color(x, y).red, metropolis.get_color(x, y).red);
+            traced_total += traced;
+            metropolis_total += sampled;
+            if traced > 0.2 {
+                assert!((traced - sampled).abs() < 0.25 * traced, "{} vs {} at {}, {}", sampled, traced, x, y);
+            }
+        }
+    }
+    assert!((traced_total - metropolis_total).abs() < 0.03 * traced_total, "{} vs {}", metropolis_total, traced_total);
+
+    // Every render starts a fresh chain for the view it's rendering, so rendering again gives the same
+    // image, and the eyes of a stereo camera each see their own
+    scene.integrator = Box::new(MetropolisIntegrator::new(Box::new(PathTracer { roulette_depth: 8 }), 1000, 1));
+    scene.sampler = Box::new(SobolSampler::new(4, 0));
+    scene.camera.stereo = Some(Stereo { eye_separation: 0.5, convergence_distance: 3.0, layout: StereoLayout::Separate });
+    let image = |eye| {
+        let film = render_film(&scene, Some(eye));
+        (0..scene.width * scene.height).map(|i| film.get_color(i % scene.width, i / scene.width).red).collect::<Vec<f32>>()
+    };
+    let left = image(Eye::Left);
+    assert_eq!(left, image(Eye::Left));
+    assert_ne!(left, image(Eye::Right));
+}
@@ -0,0 +1,418 @@
+// Wavefront OBJ/MTL import
+// REF: http://paulbourke.net/dataformats/obj/ and http://paulbourke.net/dataformats/mtl/
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::SplitWhitespace;
+use std::sync::Arc;
+
+use cgmath::{Point2, Point3, Vector3, InnerSpace};
+use image::{GenericImageView, ImageError};
+
+use crate::Color;
+use crate::material::{Material, Texture};
+use crate::mesh::Mesh;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, io::Error),
+    Image(PathBuf, ImageError),
+    Parse { file: PathBuf, line: usize, message: String },
+    Library { file: PathBuf, line: usize, error: Box<ObjError> },   // a bad MTL library, and where it's used
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ObjError::Image(path, err) => write!(f, "{}: {}", path.display(), err),
+            ObjError::Parse { file, line, message } => write!(f, "{}:{}: {}", file.display(), line, message),
+            ObjError::Library { file, line, error } => write!(f, "{}:{}: in material library: {}", file.display(), line, error),
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ObjError::Library { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// Something a file gets wrong that loading carried on past, left for the caller to report
+#[derive(Debug)]
+pub struct ObjWarning {
+    pub file: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ObjWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+    }
+}
+
+// Load every mesh in an OBJ file, along with the materials from any MTL libraries it references
+pub fn load_obj(path: &Path) -> Result<(Vec<Mesh>, Vec<ObjWarning>), ObjError> {
+    let source = fs::read_to_string(path).map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+    parse_obj(&source, path)
+}
+
+// Load every material in an MTL file, keyed by name
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, Material>, ObjError> {
+    let source = fs::read_to_string(path).map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+    parse_mtl(&source, path)
+}
+
+// Reads statements one line at a time, remembering where we are for error messages
+struct Parser<'a> {
+    file: &'a Path,
+    line: usize,
+    warnings: Vec<ObjWarning>,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: String) -> ObjError {
+        ObjError::Parse { file: self.file.to_path_buf(), line: self.line, message }
+    }
+
+    // Report something the file gets wrong that we can carry on past
+    fn warn(&mut self, message: String) {
+        self.warnings.push(ObjWarning { file: self.file.to_path_buf(), line: self.line, message });
+    }
+
+    fn float(&self, args: &mut SplitWhitespace) -> Result<f64, ObjError> {
+        let arg = args.next().ok_or_else(|| self.error("expected a number".to_string()))?;
+        arg.parse().map_err(|_| self.error(format!("invalid number '{}'", arg)))
+    }
+
+    fn color(&self, args: &mut SplitWhitespace) -> Result<Color, ObjError> {
+        let red = self.float(args)? as f32;
+        // A single value is a grey
+        if args.clone().next().is_none() {
+            return Ok(Color { red, green: red, blue: red });
+        }
+        Ok(Color { red, green: self.float(args)? as f32, blue: self.float(args)? as f32 })
+    }
+
+    // Resolve a 1-based (or negative, counting back from the latest) OBJ index into a 0-based one
+    fn index(&self, arg: &str, count: usize, kind: &str) -> Result<usize, ObjError> {
+        let index: i64 = arg.parse().map_err(|_| self.error(format!("invalid {} index '{}'", kind, arg)))?;
+        let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+        if index == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(self.error(format!("{} index {} out of range (have {})", kind, index, count)));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+// One unique combination of position, texture coordinate and normal indices
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+// A run of faces that share a group and material, which becomes one Mesh
+struct MeshBuilder {
+    material: Option<String>,
+    vertices: HashMap<VertexKey, usize>,
+    keys: Vec<VertexKey>,
+    triangles: Vec<[usize; 3]>,
+}
+
+impl MeshBuilder {
+    fn new(material: Option<String>) -> MeshBuilder {
+        MeshBuilder { material, vertices: HashMap::new(), keys: Vec::new(), triangles: Vec::new() }
+    }
+
+    fn vertex(&mut self, key: VertexKey) -> usize {
+        let keys = &mut self.keys;
+        *self.vertices.entry(key).or_insert_with(|| {
+            keys.push(key);
+            keys.len() - 1
+        })
+    }
+
+    fn build(
+        self,
+        positions: &[Point3<f64>],
+        uvs: &[Point2<f64>],
+        normals: &[Vector3<f64>],
+        materials: &HashMap<String, Material>,
+    ) -> Mesh {
+        // Attributes are only kept if every vertex has one
+        let mesh_uvs = if self.keys.iter().all(|key| key.1.is_some()) {
+            self.keys.iter().map(|key| uvs[key.1.unwrap()]).collect()
+        } else {
+            Vec::new()
+        };
+        let mesh_normals = if self.keys.iter().all(|key| key.2.is_some()) {
+            self.keys.iter().map(|key| normals[key.2.unwrap()].normalize()).collect()
+        } else {
+            Vec::new()
+        };
+        let material = self.material
+            .and_then(|name| materials.get(&name))
+            .cloned()
+            .unwrap_or_else(|| Material::matte(Color {red: 0.8, green: 0.8, blue: 0.8}));
+        Mesh::new(
+            self.keys.iter().map(|key| positions[key.0]).collect(),
+            mesh_normals,
+            mesh_uvs,
+            self.triangles,
+            material,
+        )
+    }
+}
+
+// Parse OBJ source text, returning its meshes and any warnings. `path` is used in messages and to find
+// MTL libraries next to the file.
+pub fn parse_obj(source: &str, path: &Path) -> Result<(Vec<Mesh>, Vec<ObjWarning>), ObjError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut parser = Parser { file: path, line: 0, warnings: Vec::new() };
+
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut materials = HashMap::new();
+    let mut meshes = Vec::new();
+    let mut current = MeshBuilder::new(None);
+
+    for (number, line) in source.lines().enumerate() {
+        parser.line = number + 1;
+        let line = line.split('#').next().unwrap();
+        let mut args = line.split_whitespace();
+        let statement = match args.next() {
+            Some(statement) => statement,
+            None => continue,
+        };
+
+        match statement {
+            "v" => positions.push(Point3::new(
+                parser.float(&mut args)?,
+                parser.float(&mut args)?,
+                parser.float(&mut args)?,
+            )),
+            "vt" => uvs.push(Point2::new(
+                parser.float(&mut args)?,
+                // v is optional for 1D textures
+                if args.clone().next().is_some() { parser.float(&mut args)? } else { 0.0 },
+            )),
+            "vn" => normals.push(Vector3::new(
+                parser.float(&mut args)?,
+                parser.float(&mut args)?,
+                parser.float(&mut args)?,
+            )),
+            "f" => {
+                let mut face = Vec::new();
+                for arg in args {
+                    // Each corner is v, v/vt, v//vn or v/vt/vn
+                    let mut indices = arg.split('/');
+                    let v = parser.index(indices.next().unwrap(), positions.len(), "vertex")?;
+                    let vt = match indices.next() {
+                        Some("") | None => None,
+                        Some(index) => Some(parser.index(index, uvs.len(), "texture coordinate")?),
+                    };
+                    let vn = match indices.next() {
+                        Some("") | None => None,
+                        Some(index) => Some(parser.index(index, normals.len(), "normal")?),
+                    };
+                    face.push(current.vertex((v, vt, vn)));
+                }
+                if face.len() < 3 {
+                    return Err(parser.error(format!("face needs at least 3 vertices, got {}", face.len())));
+                }
+                // Triangulate the polygon as a fan around its first corner
+                for i in 1..face.len() - 1 {
+                    current.triangles.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            "g" | "o" | "usemtl" => {
+                let material = if statement == "usemtl" {
+                    let name = args.next().ok_or_else(|| parser.error("usemtl needs a name".to_string()))?;
+                    // Real assets often name materials their library lacks; those meshes get the default
+                    if !materials.contains_key(name) {
+                        parser.warn(format!("unknown material '{}', using the default", name));
+                    }
+                    Some(name.to_string())
+                } else {
+                    current.material.clone()
+                };
+                // Each group and material change starts a new mesh, carrying the material across groups
+                let previous = std::mem::replace(&mut current, MeshBuilder::new(material));
+                if !previous.triangles.is_empty() {
+                    meshes.push(previous.build(&positions, &uvs, &normals, &materials));
+                }
+            }
+            "mtllib" => {
+                for library in args {
+                    let library = load_mtl(&base_dir.join(library)).map_err(|error| ObjError::Library {
+                        file: path.to_path_buf(),
+                        line: parser.line,
+                        error: Box::new(error),
+                    })?;
+                    materials.extend(library);
+                }
+            }
+            // Smoothing groups, lines, points and free-form geometry don't affect triangle meshes
+            _ => {}
+        }
+    }
+
+    if !current.triangles.is_empty() {
+        meshes.push(current.build(&positions, &uvs, &normals, &materials));
+    }
+    Ok((meshes, parser.warnings))
+}
+
+// Parse MTL source text. `path` is used in error messages and to find texture maps next to the file.
+pub fn parse_mtl(source: &str, path: &Path) -> Result<HashMap<String, Material>, ObjError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut parser = Parser { file: path, line: 0, warnings: Vec::new() };
+
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, Material)> = None;
+
+    for (number, line) in source.lines().enumerate() {
+        parser.line = number + 1;
+        let line = line.split('#').next().unwrap();
+        let mut args = line.split_whitespace();
+        let statement = match args.next() {
+            Some(statement) => statement,
+            None => continue,
+        };
+
+        if statement == "newmtl" {
+            let name = args.next().ok_or_else(|| parser.error("newmtl needs a name".to_string()))?;
+            let material = Material::matte(Color {red: 0.8, green: 0.8, blue: 0.8});
+            if let Some((name, material)) = current.replace((name.to_string(), material)) {
+                materials.insert(name, material);
+            }
+            continue;
+        }
+
+        let material = match current.as_mut() {
+            Some((_, material)) => material,
+            None => return Err(parser.error(format!("'{}' before any newmtl", statement))),
+        };
+        match statement {
+            "Kd" => material.color = parser.color(&mut args)?,
+            "Ks" => material.specular = parser.color(&mut args)?,
+            "Ns" => material.shininess = parser.float(&mut args)? as f32,
+            "d" => material.opacity = parser.float(&mut args)? as f32,
+            "Tr" => material.opacity = 1.0 - parser.float(&mut args)? as f32,
+            "Ni" => material.refractive_index = parser.float(&mut args)?,
+            "Ke" => material.emission = parser.color(&mut args)?,
+            "map_Kd" => {
+                // Options such as -s or -o may come first; the file name is always last
+                let file = args.last().ok_or_else(|| parser.error("map_Kd needs a file name".to_string()))?;
+                let texture_path = base_dir.join(file);
+                let texture = Texture::open(&texture_path).map_err(|err| ObjError::Image(texture_path, err))?;
+                // An image with no pixels has no color to look up
+                if texture.image.width() == 0 || texture.image.height() == 0 {
+                    return Err(parser.error(format!("texture '{}' is empty", file)));
+                }
+                material.texture = Some(Arc::new(texture));
+            }
+            // Ambient and illumination model settings have no equivalent in our materials
+            _ => {}
+        }
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+    Ok(materials)
+}
+
+#[test]
+fn test_parse_obj() {
+    let source = "
+        # A quad with normals and texture coordinates, then a triangle in its own group
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0 1 0
+        vt 0 0
+        vt 1 0
+        vt 1 1
+        vt 0 1
+        vn 0 0 1
+        f 1/1/1 2/2/1 3/3/1 4/4/1
+        g second
+        v 0 0 -1
+        f -1 -4 -3
+    ";
+    let (meshes, warnings) = parse_obj(source, Path::new("test.obj")).unwrap();
+    assert!(warnings.is_empty());
+    assert_eq!(meshes.len(), 2);
+
+    assert_eq!(meshes[0].triangles(), &[[0, 1, 2], [0, 2, 3]]);
+    assert_eq!(meshes[0].normals().len(), 4);
+    assert_eq!(meshes[0].uvs()[2], Point2::new(1.0, 1.0));
+
+    assert_eq!(meshes[1].positions(), &[Point3::new(0.0, 0.0, -1.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0)]);
+    assert!(meshes[1].normals().is_empty());
+
+    match parse_obj("v 0 0 0\nv 1 0 0\nf 1 2 3\n", Path::new("bad.obj")) {
+        Err(ObjError::Parse { line, .. }) => assert_eq!(line, 3),
+        _ => panic!("expected a parse error"),
+    }
+
+    // A material the libraries don't have falls back to the default, with a warning for the caller
+    use crate::Intersectable;
+    let (meshes, warnings) = parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl missing\nf 1 2 3\n", Path::new("test.obj")).unwrap();
+    assert_eq!(meshes[0].material().color, Color {red: 0.8, green: 0.8, blue: 0.8});
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].to_string(), "test.obj:4: unknown material 'missing', using the default");
+
+    // A library that can't be loaded is reported where the OBJ file asks for it
+    match parse_obj("v 0 0 0\nmtllib missing.mtl\n", Path::new("test.obj")) {
+        Err(ObjError::Library { line, error, .. }) => {
+            assert_eq!(line, 2);
+            assert!(matches!(*error, ObjError::Io(..)));
+        }
+        _ => panic!("expected a library error"),
+    }
+}
+
+#[test]
+fn test_parse_mtl() {
+    let source = "
+        newmtl glass
+        Kd 0.1 0.2 0.3
+        Ks 1 1 1
+        Ns 96
+        d 0.25
+        Ni 1.5
+        Ke 0 0.5 0
+        illum 4
+    ";
+    let materials = parse_mtl(source, Path::new("test.mtl")).unwrap();
+    let glass = &materials["glass"];
+    assert_eq!(glass.color, Color {red: 0.1, green: 0.2, blue: 0.3});
+    assert_eq!(glass.specular, Color {red: 1.0, green: 1.0, blue: 1.0});
+    assert_eq!(glass.shininess, 96.0);
+    assert_eq!(glass.opacity, 0.25);
+    assert_eq!(glass.refractive_index, 1.5);
+    assert_eq!(glass.emission, Color {red: 0.0, green: 0.5, blue: 0.0});
+
+    match parse_mtl("newmtl a\nKd 1 x 1\n", Path::new("bad.mtl")) {
+        Err(ObjError::Parse { line, .. }) => assert_eq!(line, 2),
+        _ => panic!("expected a parse error"),
+    }
+
+    // Image formats that allow an image with no pixels load fine, but it can't be used as a texture
+    let empty = std::env::temp_dir().join("obj_test_empty_texture.ppm");
+    fs::write(&empty, "P6\n0 0\n255\n").unwrap();
+    let source = format!("newmtl a\nmap_Kd {}\n", empty.display());
+    match parse_mtl(&source, Path::new("empty.mtl")) {
+        Err(ObjError::Parse { line, .. }) => assert_eq!(line, 2),
+        _ => panic!("expected an empty texture to be rejected"),
+    }
+    fs::remove_file(&empty).unwrap();
+}
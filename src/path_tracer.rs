@@ -0,0 +1,206 @@
+// Unidirectional path tracing: follow light backwards from the camera, bouncing off surfaces by sampling
+// their BSDFs, and at every bounce also sample a light directly (next-event estimation). Emissive
+// objects can be found both ways, so the two estimates are blended by multiple importance sampling;
+// point, spot and directional lights can only be reached by sampling them.
+// REF: Veach, "Robust Monte Carlo Methods for Light Transport Simulation", PhD thesis, 1997, chapter 9
+
+use cgmath::{Point2, Point3, Vector3, InnerSpace, MetricSpace};
+
+use crate::{Color, Intersection, Ray, Scene};
+use crate::bsdf::Bsdf;
+use crate::integrator::Integrator;
+use crate::sampler::Sampler;
+
+pub struct PathTracer {
+    pub roulette_depth: u32,    // bounces before paths may be ended early by Russian roulette
+}
+
+// Weight for one of two sampling strategies that could have produced the same path, by the densities
+// with which each would have
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 { 0.0 } else { a / (a + b) }
+}
+
+// Light given off by the surface towards `wo`, from the front of the surface only
+pub fn emitted(hit: &Intersection, wo: Vector3<f64>) -> Color {
+    if hit.normal.dot(wo) > 0.0 { hit.object.material().emission } else { Color::black() }
+}
+
+// The solid angle density with which sampling one of `emitter_count` emitters picks the point `hit`,
+// as seen from `origin`
+pub fn emitter_pdf(emitter_count: usize, hit: &Intersection, origin: Point3<f64>) -> f64 {
+    let area = hit.object.area();
+    if emitter_count == 0 || !area.is_finite() {
+        return 0.0;
+    }
+    let to_light = hit.point - origin;
+    let cosine = hit.normal.dot(to_light.normalize()).abs();
+    if cosine == 0.0 {
+        return 0.0;
+    }
+    to_light.magnitude2() / (cosine * area * emitter_count as f64)
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        let emitters = scene.emitters();
+        let mut radiance = Color::black();
+        let mut throughput = Color::white();
+        let mut ray = Ray { origin: ray.origin, direction: ray.direction };
+        // Camera rays and mirror bounces can't sample lights, so whatever light they hit counts in full
+        let mut specular_bounce = true;
+        let mut bsdf_pdf = 0.0;
+
+        for depth in 0..=scene.max_recursion_depth {
+            let hit = match scene.trace(&ray) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let wo = -ray.direction;
+
+            let emission = emitted(&hit, wo);
+            if emission != Color::black() {
+                let weight = if specular_bounce {
+                    1.0
+                } else {
+                    power_heuristic(bsdf_pdf, emitter_pdf(emitters.len(), &hit, ray.origin))
+                };
+                radiance = radiance + throughput * emission * weight as f32;
+            }
+
+            // Every bounce draws the same dimensions, so each one keeps its place in the sampler's sequence
+            let light_choice = sampler.get_1d();
+            let light_sample = sampler.get_2d();
+            let lobe = sampler.get_1d();
+            let direction_sample = sampler.get_2d();
+            let roulette = sampler.get_1d();
+
+            let bsdf = Bsdf::new(&hit);
+            if bsdf.is_rough() {
+                radiance = radiance + throughput * sample_lights(scene, &hit, &bsdf, wo, light_choice, light_sample);
+            }
+
+            let sample = match bsdf.sample(wo, lobe, direction_sample) {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput = throughput * sample.weight;
+            specular_bounce = sample.specular;
+            bsdf_pdf = sample.pdf;
+            ray = Ray::create_bounce(hit.normal, sample.direction, hit.point, scene.shadow_bias);
+
+            // Past the first few bounces, end dim paths at random and boost the survivors to make up for it
+            if depth + 1 >= self.roulette_depth {
+                let survival = throughput.red.max(throughput.green).max(throughput.blue).min(0.95) as f64;
+                if roulette >= survival {
+                    break;
+                }
+                throughput = throughput * (1.0 / survival) as f32;
+            }
+        }
+        radiance
+    }
+}
+
+// Light reaching `point` straight from every point, spot and directional light, scattered towards `wo`
+pub fn delta_lighting(scene: &Scene, point: Point3<f64>, bsdf: &Bsdf, wo: Vector3<f64>) -> Color {
+    let normal = bsdf.normal();
+    let mut color = Color::black();
+    for light in &scene.lights {
+        let wi = light.direction_from(&point);
+        let f = bsdf.evaluate(wo, wi);
+        if f == Color::black() {
+            continue;
+        }
+        let shadow_ray = Ray::create_bounce(normal, wi, point, scene.shadow_bias);
+        if scene.occluded(&shadow_ray, light.distance(&shadow_ray.origin)) {
+            continue;
+        }
+        let cosine = wi.dot(normal).abs() as f32;
+        color = color + f * *light.color() * (light.intensity(&point) * cosine);
+    }
+    color
+}
+
+// Light reaching `hit` directly and scattered towards `wo`: from every point, spot and directional light,
+// plus one point on one emitter picked at random
+pub fn sample_lights(
+    scene: &Scene,
+    hit: &Intersection,
+    bsdf: &Bsdf,
+    wo: Vector3<f64>,
+    light_choice: f64,
+    light_sample: Point2<f64>,
+) -> Color {
+    let normal = bsdf.normal();
+    let color = delta_lighting(scene, hit.point, bsdf, wo);
+
+    let emitters = scene.emitters();
+    if emitters.is_empty() {
+        return color;
+    }
+    let emitter = emitters.get(((light_choice * emitters.len() as f64) as usize).min(emitters.len() - 1));
+    let point = match emitter.sample_surface(light_sample) {
+        Some(point) => point,
+        None => return color,
+    };
+    let to_light = point.point - hit.point;
+    let distance = to_light.magnitude();
+    let wi = to_light / distance;
+    let light_cosine = point.normal.dot(-wi);
+    if light_cosine <= 0.0 {
+        return color;
+    }
+    let f = bsdf.evaluate(wo, wi);
+    if f == Color::black() {
+        return color;
+    }
+    let shadow_ray = Ray::create_bounce(normal, wi, hit.point, scene.shadow_bias);
+    if scene.occluded(&shadow_ray, shadow_ray.origin.distance(point.point) - 2.0 * scene.shadow_bias) {
+        return color;
+    }
+
+    let light_pdf = point.point.distance2(hit.point) / (light_cosine * emitter.area() * emitters.len() as f64);
+    let weight = power_heuristic(light_pdf, bsdf.pdf(wo, wi));
+    let cosine = wi.dot(normal).abs();
+    color + f * emitter.material().emission * (cosine * weight / light_pdf) as f32
+}
+
+#[test]
+fn test_path_tracer() {
+    use crate::{cast_ray, estimate_radiance, lamp_scene, Plane};
+    use crate::bvh::Aggregate;
+    use crate::light::{Light, PointLight};
+    use crate::material::Material;
+
+    let mut scene = lamp_scene(vec![]);
+    let tracer = PathTracer { roulette_depth: 3 };
+
+    // The lamp is the only emitter; the floor is unlit and infinite
+    assert_eq!(scene.emitters().len(), 1);
+    assert_eq!(scene.emitters().get(0).area(), std::f64::consts::PI);
+
+    // Straight down at the point under the lamp, the floor shows a quarter of the lamp's brightness
+    let down = Ray { origin: Point3::new(0.0, 0.5, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+    let floor = estimate_radiance(&scene, &tracer, &down, 2048);
+    assert!((floor.red - 0.25).abs() < 0.01, "{:?}", floor);
+
+    // Looking up, the lamp is seen directly
+    let up = Ray { origin: Point3::new(0.0, 0.5, 0.0), direction: Vector3::new(0.0, 1.0, 0.0) };
+    assert!((estimate_radiance(&scene, &tracer, &up, 2048).red - 1.0).abs() < 1e-6);
+
+    // With only a point light, a diffuse floor looks just as it does with Whitted shading
+    scene.objects = Aggregate::new(vec![
+        Box::new(Plane {
+            point: Point3::new(0.0, 0.0, 0.0),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            one_sided: false,
+            material: Material::matte(Color {red: 0.5, green: 0.5, blue: 0.5}),
+        }),
+    ]);
+    scene.lights = vec![Light::Point(PointLight { position: Point3::new(1.0, 2.0, 0.0), color: Color::white(), intensity: 100.0 })];
+    let whitted = cast_ray(&scene, &down, 0);
+    let traced = estimate_radiance(&scene, &tracer, &down, 2048);
+    assert!((whitted.red - traced.red).abs() < 1e-5);
+}
@@ -0,0 +1,353 @@
+// Photon mapping: trace photons out from the lights, store where they land on rough surfaces, and
+// estimate the light bouncing off a visible point from the density of photons around it. Photons go
+// through glass and off mirrors as easily as anywhere else, so this finds caustics that camera paths
+// only stumble on, such as a lamp focused through a glass ball.
+//
+// Light reaching a surface straight from the lights is still sampled from the camera side, so photons
+// are only stored once they've bounced at least once. Point and spot lights emit photons too; directional
+// lights have nowhere to emit them from, so they only give direct light.
+//
+// Each iteration traces a fresh photon map and gathers over a slightly smaller radius than the last.
+// Averaging the iterations shrinks the blur that density estimation adds, until with enough of them the
+// image converges to the right answer.
+// REF: Jensen, "Realistic Image Synthesis Using Photon Mapping", 2001
+// REF: Knaus and Zwicker, "Progressive Photon Mapping: A Probabilistic Approach", ACM TOG 2011
+
+use std::f64::consts::PI;
+
+use cgmath::{Point2, Point3, Vector3, InnerSpace, MetricSpace};
+
+use crate::{orthonormal_basis, Color, Ray, Scene};
+use crate::bsdf::{cosine_hemisphere, Bsdf};
+use crate::camera::Eye;
+use crate::integrator::{trace_direct, Integrator, RenderPass};
+use crate::light::Light;
+use crate::sampler::{IndependentSampler, Sampler};
+
+pub struct Photon {
+    pub point: Point3<f64>,
+    pub wi: Vector3<f64>,   // unit vector back towards where the photon came from
+    pub power: Color,
+}
+
+// A kd-tree of photons, kept implicitly: each range of the array is split at its middle photon, along
+// the axis the range is longest in, with the photons on either side of it in the two halves
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,       // the splitting axis at each photon
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // Call `f` with every photon within `radius` of `point`
+    pub fn for_each_within(&self, point: Point3<f64>, radius: f64, f: &mut dyn FnMut(&Photon)) {
+        within(&self.photons, &self.axes, point, radius * radius, f);
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.is_empty() {
+        return;
+    }
+    let mut min = photons[0].point;
+    let mut max = photons[0].point;
+    for photon in photons.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(photon.point[axis]);
+            max[axis] = max[axis].max(photon.point[axis]);
+        }
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| a.point[axis].total_cmp(&b.point[axis]));
+    axes[middle] = axis;
+    let (photons_below, photons_above) = photons.split_at_mut(middle);
+    let (axes_below, axes_above) = axes.split_at_mut(middle);
+    build(photons_below, axes_below);
+    build(&mut photons_above[1..], &mut axes_above[1..]);
+}
+
+fn within(photons: &[Photon], axes: &[usize], point: Point3<f64>, radius2: f64, f: &mut dyn FnMut(&Photon)) {
+    if photons.is_empty() {
+        return;
+    }
+    let middle = photons.len() / 2;
+    let photon = &photons[middle];
+    if photon.point.distance2(point) <= radius2 {
+        f(photon);
+    }
+    // Search the half the point is in, then the other only if the sphere reaches across the split
+    let offset = point[axes[middle]] - photon.point[axes[middle]];
+    let below = (&photons[..middle], &axes[..middle]);
+    let above = (&photons[middle + 1..], &axes[middle + 1..]);
+    let (near, far) = if offset < 0.0 { (below, above) } else { (above, below) };
+    within(near.0, near.1, point, radius2, f);
+    if offset * offset <= radius2 {
+        within(far.0, far.1, point, radius2, f);
+    }
+}
+
+// Progressive photon mapping in `iterations` passes over the image. Each pass traces a fresh photon map
+// of `photons_per_iteration` photons, renders every pixel with it, and drops it, so only one map is
+// held at a time; the film averages the passes. Gathering starts out over `initial_radius`, and each
+// pass's area shrinks by (i + alpha) / (i + 1) from the last.
+pub struct PhotonMapper {
+    pub photons_per_iteration: u32,
+    pub iterations: u32,
+    pub initial_radius: f64,
+    pub alpha: f64,
+}
+
+// One pass's photons, and the radius they're gathered over
+struct PhotonPass {
+    map: PhotonMap,
+    radius: f64,
+}
+
+impl PhotonMapper {
+    pub fn new(photons_per_iteration: u32, iterations: u32, initial_radius: f64) -> PhotonMapper {
+        PhotonMapper {
+            photons_per_iteration,
+            iterations: iterations.max(1),
+            initial_radius,
+            // Knaus and Zwicker's suggested balance between shrinking the bias and the noise
+            alpha: 2.0 / 3.0,
+        }
+    }
+
+    // The radius photons are gathered over in the given iteration, counting from 0
+    pub fn radius(&self, iteration: u32) -> f64 {
+        let mut area = self.initial_radius * self.initial_radius;
+        for i in 1..=iteration {
+            area *= (i as f64 + self.alpha) / (i as f64 + 1.0);
+        }
+        area.sqrt()
+    }
+
+    // Trace one iteration's photons from the lights and store those that land on rough surfaces
+    pub fn trace_photons(&self, scene: &Scene, iteration: u32) -> PhotonMap {
+        let emitters = scene.emitters();
+        let lights: Vec<&Light> = scene.lights.iter().filter(|light| !matches!(light, Light::Directional(_))).collect();
+        let sources = emitters.len() + lights.len();
+        let mut photons = vec![];
+        if sources == 0 {
+            return PhotonMap::new(photons);
+        }
+        // Every photon gets the same share of the lights' total power, whichever light it leaves from
+        let share = sources as f32 / self.photons_per_iteration as f32;
+
+        let mut sampler = IndependentSampler::new(1, 0);
+        for index in 0..self.photons_per_iteration {
+            sampler.start_pixel_sample((iteration, 0), index);
+            let light_choice = sampler.get_1d();
+            let position_sample = sampler.get_2d();
+            let direction_sample = sampler.get_2d();
+
+            let choice = ((light_choice * sources as f64) as usize).min(sources - 1);
+            let (mut ray, mut power) = if choice < emitters.len() {
+                // A point picked uniformly over the emitter, shining in a cosine-weighted direction: the
+                // cosines cancel, leaving the emission times pi times the area
+                let emitter = emitters.get(choice);
+                let surface = match emitter.sample_surface(position_sample) {
+                    Some(surface) => surface,
+                    None => continue,
+                };
+                let direction = cosine_hemisphere(surface.normal, direction_sample);
+                let ray = Ray::create_bounce(surface.normal, direction, surface.point, scene.shadow_bias);
+                (ray, emitter.material().emission * (PI * emitter.area()) as f32)
+            } else {
+                // A direction picked uniformly over the sphere, or over a spot light's cone. Intensity at a
+                // unit distance is the light's power per unit solid angle.
+                let (position, axis, cos_max) = match lights[choice - emitters.len()] {
+                    Light::Point(point) => (point.position, Vector3::new(0.0, 1.0, 0.0), -1.0),
+                    Light::Spot(spot) => (spot.position, spot.direction.normalize(), spot.angle.to_radians().cos()),
+                    Light::Directional(_) => unreachable!(),
+                };
+                let light = lights[choice - emitters.len()];
+                let direction = uniform_cone(axis, cos_max, direction_sample);
+                let solid_angle = 2.0 * PI * (1.0 - cos_max);
+                let intensity = light.intensity(&(position + direction)) as f64;
+                (Ray { origin: position, direction }, *light.color() * (intensity * solid_angle) as f32)
+            };
+            power = power * share;
+
+            for depth in 0..=scene.max_recursion_depth {
+                let hit = match scene.trace(&ray) {
+                    Some(hit) => hit,
+                    None => break,
+                };
+                let wi = -ray.direction;
+                let bsdf = Bsdf::new(&hit);
+                if depth > 0 && bsdf.is_rough() {
+                    photons.push(Photon { point: hit.point, wi, power });
+                }
+
+                let lobe = sampler.get_1d();
+                let direction_sample = sampler.get_2d();
+                let roulette = sampler.get_1d();
+                // The BSDF is symmetric, so the directions light comes from can be sampled for the ones
+                // it leaves in
+                let sample = match bsdf.sample(wi, lobe, direction_sample) {
+                    Some(sample) => sample,
+                    None => break,
+                };
+                // Keep each photon's power about the same by ending paths in proportion to what's absorbed
+                let survival = sample.weight.red.max(sample.weight.green).max(sample.weight.blue).min(0.95) as f64;
+                if roulette >= survival {
+                    break;
+                }
+                power = power * sample.weight * (1.0 / survival) as f32;
+                ray = Ray::create_bounce(hit.normal, sample.direction, hit.point, scene.shadow_bias);
+            }
+        }
+        PhotonMap::new(photons)
+    }
+}
+
+// A direction picked uniformly from those within the cone about `axis` out to `cos_max`
+fn uniform_cone(axis: Vector3<f64>, cos_max: f64, sample: Point2<f64>) -> Vector3<f64> {
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    let cos_theta = 1.0 - sample.x * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * sample.y;
+    (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta).normalize()
+}
+
+impl Integrator for PhotonMapper {
+    // Photons are only traced for a render's passes, so on its own this finds just the light camera paths
+    // reach by themselves: emitters seen directly or through mirrors and glass, and direct lighting
+    fn radiance(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> Color {
+        trace_direct(scene, ray, sampler, &|_, _, _| Color::black())
+    }
+
+    // At least one pass, however the mapper was built, or nothing would be rendered at all
+    fn passes(&self) -> u32 {
+        self.iterations.max(1)
+    }
+
+    fn begin_pass<'a>(&'a self, scene: &'a Scene, _eye: Option<Eye>, pass: u32) -> Option<Box<dyn RenderPass + 'a>> {
+        Some(Box::new(PhotonPass { map: self.trace_photons(scene, pass), radius: self.radius(pass) }))
+    }
+}
+
+impl RenderPass for PhotonPass {
+    fn radiance_and_splats(
+        &mut self,
+        scene: &Scene,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        _splat: &mut dyn FnMut(Point2<f64>, Color),
+    ) -> Color {
+        let (map, radius) = (&self.map, self.radius);
+        // Each photon's power, scattered towards the viewer, spread over the disk it was gathered from
+        trace_direct(scene, ray, sampler, &|hit, bsdf, wo| {
+            let mut total = Color::black();
+            map.for_each_within(hit.point, radius, &mut |photon| total = total + bsdf.evaluate(wo, photon.wi) * photon.power);
+            total * (1.0 / (PI * radius * radius)) as f32
+        })
+    }
+}
+
+#[test]
+fn test_photon_map() {
+    use crate::{estimate_radiance, lamp_scene, render_film, Sphere};
+    use crate::camera::Camera;
+    use crate::integrator::DirectLightingIntegrator;
+    use crate::material::Material;
+    use crate::path_tracer::PathTracer;
+    use crate::sampler::SobolSampler;
+
+    // Searching the tree finds the same photons as checking them all
+    let mut sampler = IndependentSampler::new(1, 5);
+    sampler.start_pixel_sample((0, 0), 0);
+    let mut photons = vec![];
+    for _ in 0..500 {
+        let point = Point3::new(sampler.get_1d(), sampler.get_1d(), sampler.get_1d() * 0.1);
+        photons.push(Photon { point, wi: Vector3::new(0.0, 1.0, 0.0), power: Color::white() });
+    }
+    let center = Point3::new(0.4, 0.6, 0.05);
+    let expected = photons.iter().filter(|photon| photon.point.distance(center) <= 0.2).count();
+    let map = PhotonMap::new(photons);
+    assert_eq!(map.len(), 500);
+    let mut found = 0;
+    map.for_each_within(center, 0.2, &mut |photon| {
+        assert!(photon.point.distance(center) <= 0.2);
+        found += 1;
+    });
+    assert!(expected > 0 && found == expected);
+
+    // A photon with no proper position, such as one bounced off a degenerate normal, can't be found but
+    // doesn't stop the tree being built
+    let stray = |x| Photon { point: Point3::new(x, 0.5, 0.5), wi: Vector3::new(0.0, 1.0, 0.0), power: Color::white() };
+    let map = PhotonMap::new(vec![stray(0.4), stray(f64::NAN), stray(0.6)]);
+    let mut found = 0;
+    map.for_each_within(Point3::new(0.5, 0.5, 0.5), 0.2, &mut |_| found += 1);
+    assert_eq!(found, 2);
+
+    // The gathering radius shrinks each iteration, more slowly as it goes
+    let mapper = PhotonMapper::new(20000, 8, 0.1);
+    assert_eq!(mapper.radius(0), 0.1);
+    assert!(mapper.radius(1) < 0.1 && mapper.radius(2) - mapper.radius(3) < 0.1 - mapper.radius(1));
+    // Even a mapper built with no iterations renders once
+    assert_eq!(PhotonMapper { iterations: 0, ..PhotonMapper::new(20000, 8, 0.1) }.passes(), 1);
+
+    // The lamp scene with a ball beside the lamp to bounce light back down. Beside the ball, the floor's
+    // direct and indirect light agree with path tracing.
+    let mut scene = lamp_scene(vec![
+        Box::new(Sphere {
+            center: Point3::new(1.0, 0.3, 0.0),
+            radius: 0.3,
+            material: Material::matte(Color::white()),
+        }),
+    ]);
+    scene.max_recursion_depth = 4;
+    scene.integrator = Box::new(PhotonMapper::new(20000, 8, 0.1));
+    let path_tracer = PathTracer { roulette_depth: 8 };
+    let beside = Ray { origin: Point3::new(0.5, 0.5, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+    let mapped = estimate_radiance(&scene, &mapper, &beside, 1024).red;
+    let traced = estimate_radiance(&scene, &path_tracer, &beside, 1024).red;
+    assert!((mapped - traced).abs() < 0.02 * traced, "{} vs {}", mapped, traced);
+
+    // Rendering makes a pass over the image for each iteration, each carrying on through the samples
+    scene.camera = Camera {
+        position: Point3::new(0.5, 0.5, 0.0),
+        look_at: Point3::new(0.5, 0.0, 0.0),
+        up: Vector3::new(0.0, 0.0, -1.0),
+        fov: 0.1,
+        ..Camera::default()
+    };
+    scene.sampler = Box::new(SobolSampler::new(128, 1));
+    let rendered = render_film(&scene, None).get_color(0, 0).red;
+    assert!((rendered - traced).abs() < 0.02 * traced, "{} vs {}", rendered, traced);
+
+    // Under a glass ball, most of the floor's light is focused through the ball, where the shadow rays of
+    // direct lighting can't follow
+    scene.objects = lamp_scene(vec![
+        Box::new(Sphere {
+            center: Point3::new(0.0, 0.5, 0.0),
+            radius: 0.3,
+            material: Material::transparent(1.5),
+        }),
+    ]).objects;
+    let mapper = PhotonMapper::new(20000, 8, 0.1);
+    let below = Ray { origin: Point3::new(0.0, 0.1, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+    let mapped = estimate_radiance(&scene, &mapper, &below, 1024).red;
+    let traced = estimate_radiance(&scene, &path_tracer, &below, 1024).red;
+    assert!(mapped > 2.0 * estimate_radiance(&scene, &DirectLightingIntegrator, &below, 1024).red);
+    assert!((mapped - traced).abs() < 0.05 * traced, "{} vs {}", mapped, traced);
+}
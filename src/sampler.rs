@@ -0,0 +1,597 @@
+// Sample generators for the many dimensions of each pixel sample: where it lands in the pixel, where
+// it passes through the lens, and the choices made at every bounce. Renderers call `start_pixel_sample`
+// and then draw dimensions in a fixed order, so every sampler is deterministic given its seed, the
+// pixel and the sample's index within the pixel.
+// REF: Pharr, Jakob and Humphreys, "Physically Based Rendering", 4th ed., chapter 8
+
+use std::sync::Arc;
+
+use cgmath::Point2;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+// The largest f64 below 1, so samples stay in [0, 1)
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+pub trait Sampler {
+    // How many samples each pixel is meant to take
+    fn samples_per_pixel(&self) -> u32;
+    // Begin sample `index` of `pixel`, restarting from the first dimension
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32);
+    // The next dimension of the current sample
+    fn get_1d(&mut self) -> f64;
+    // The next two dimensions of the current sample, e.g. a position in the pixel or on the lens
+    fn get_2d(&mut self) -> Point2<f64>;
+    // A fresh copy to take samples with, leaving this one as configuration
+    fn clone_sampler(&self) -> Box<dyn Sampler>;
+}
+
+// Keep sampling a pixel past the sampler's samples per pixel while the relative standard error of its
+// mean brightness is above `threshold`, up to `max_samples` in all. Flat regions stop early, leaving the
+// time for edges, soft shadows and other noisy parts of the image.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSampling {
+    pub threshold: f64,
+    pub max_samples: u32,
+}
+
+// Which pixel sample a sampler is on, and how many dimensions it has handed out so far
+#[derive(Clone, Copy, Default)]
+struct SampleState {
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+}
+
+impl SampleState {
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        *self = SampleState { pixel, index, dimension: 0 };
+    }
+
+    // Hand out the next `count` dimensions, returning the first
+    fn take(&mut self, count: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += count;
+        dimension
+    }
+
+    // A hash of the pixel and dimension, the same for every sample of the pixel
+    fn pixel_hash(&self, seed: u64, dimension: u32) -> u64 {
+        hash(&[seed, self.pixel.0 as u64, self.pixel.1 as u64, dimension as u64])
+    }
+}
+
+// Uniform random samples with no structure at all
+#[derive(Clone)]
+pub struct IndependentSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    rng: SmallRng,
+}
+
+// Jittered samples, one in each stratum of a grid over every pair of dimensions, with the strata of
+// different dimensions shuffled against each other
+#[derive(Clone)]
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    state: SampleState,
+}
+
+// The Halton sequence, one prime base per dimension, randomised per pixel by a random toroidal shift
+#[derive(Clone)]
+pub struct HaltonSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    state: SampleState,
+}
+
+// The first two dimensions of the Sobol sequence, padded out to any number of dimensions by shuffling
+// the samples' order for each pair and Owen scrambling every dimension
+#[derive(Clone)]
+pub struct SobolSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    state: SampleState,
+}
+
+// Every pixel shares the same Sobol points, each pixel shifting them by the value of a blue noise mask,
+// so what error is left over is spread out as high-frequency noise rather than blotches
+// REF: Georgiev and Fajardo, "Blue-noise Dithered Sampling", SIGGRAPH 2016 Talks
+#[derive(Clone)]
+pub struct BlueNoiseSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    mask: Arc<Vec<f64>>,
+    state: SampleState,
+}
+
+impl IndependentSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> IndependentSampler {
+        IndependentSampler { samples_per_pixel, seed, rng: SmallRng::seed_from_u64(seed) }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.rng = SmallRng::seed_from_u64(hash(&[self.seed, pixel.0 as u64, pixel.1 as u64, index as u64]));
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> Point2<f64> {
+        Point2::new(self.rng.gen(), self.rng.gen())
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> StratifiedSampler {
+        StratifiedSampler { samples_per_pixel, seed, state: SampleState::default() }
+    }
+
+    // A random number for the current sample and dimension, for jittering within its stratum
+    fn jitter(&self, dimension: u32) -> f64 {
+        let state = &self.state;
+        to_unit(hash(&[self.seed, state.pixel.0 as u64, state.pixel.1 as u64, state.index as u64, dimension as u64]))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let dimension = self.state.take(1);
+        let count = self.samples_per_pixel.max(1);
+        let jitter = self.jitter(dimension);
+        // Samples past the planned count have no stratum left, so land anywhere
+        if self.state.index >= count {
+            return jitter;
+        }
+        let stratum = permutation_element(self.state.index, count, self.state.pixel_hash(self.seed, dimension) as u32);
+        ((stratum as f64 + jitter) / count as f64).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> Point2<f64> {
+        let dimension = self.state.take(2);
+        // As square a grid as the sample count allows; any samples that don't fill it land anywhere
+        let columns = ((self.samples_per_pixel as f64).sqrt() as u32).max(1);
+        let rows = (self.samples_per_pixel / columns).max(1);
+        let (jitter_x, jitter_y) = (self.jitter(dimension), self.jitter(dimension + 1));
+        if self.state.index >= columns * rows {
+            return Point2::new(jitter_x, jitter_y);
+        }
+        let stratum = permutation_element(self.state.index, columns * rows, self.state.pixel_hash(self.seed, dimension) as u32);
+        Point2::new(
+            (((stratum % columns) as f64 + jitter_x) / columns as f64).min(ONE_MINUS_EPSILON),
+            (((stratum / columns) as f64 + jitter_y) / rows as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// Bases for the Halton sequence's dimensions; dimensions beyond these fall back to random numbers
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+// Mirror the digits of `index` in `base` about the decimal point
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    result
+}
+
+impl HaltonSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> HaltonSampler {
+        HaltonSampler { samples_per_pixel, seed, state: SampleState::default() }
+    }
+
+    fn sample(&self, dimension: u32) -> f64 {
+        let shift = to_unit(self.state.pixel_hash(self.seed, dimension));
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => wrap(radical_inverse(base, self.state.index) + shift),
+            None => to_unit(hash(&[shift.to_bits(), self.state.index as u64])),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let dimension = self.state.take(1);
+        self.sample(dimension)
+    }
+
+    fn get_2d(&mut self) -> Point2<f64> {
+        let dimension = self.state.take(2);
+        Point2::new(self.sample(dimension), self.sample(dimension + 1))
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// The first two dimensions of the Sobol sequence as 32-bit fixed point fractions. The first is the
+// van der Corput sequence; together they form a (0, 2)-sequence, so every power-of-two run of samples
+// puts one point in each cell of any grid of that many cells.
+// REF: Kollig and Keller, "Efficient Multidimensional Sampling", Eurographics 2002
+fn sobol_2d(mut index: u32) -> (u32, u32) {
+    let x = index.reverse_bits();
+    let mut y = 0;
+    let mut direction = 1u32 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            y ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    (x, y)
+}
+
+// Randomly flip bits of `v` such that each bit's flip depends only on the bits above it, which keeps
+// the sequence's stratification intact
+// REF: Burley, "Practical Hash-based Owen Scrambling", JCGT 2020
+fn owen_scramble(mut v: u32, seed: u32) -> u32 {
+    v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+fn fixed_to_unit(v: u32) -> f64 {
+    (v as f64 / 4294967296.0).min(ONE_MINUS_EPSILON)
+}
+
+impl SobolSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> SobolSampler {
+        SobolSampler { samples_per_pixel, seed, state: SampleState::default() }
+    }
+
+    // The sample's position in the sequence for this pixel and pair of dimensions
+    fn shuffled_index(&self, dimension: u32) -> u32 {
+        shuffle_in_runs(self.state.index, self.samples_per_pixel, self.state.pixel_hash(self.seed, dimension))
+    }
+
+    fn scramble(&self, v: u32, dimension: u32) -> f64 {
+        let seed = (self.state.pixel_hash(self.seed, dimension) >> 32) as u32;
+        fixed_to_unit(owen_scramble(v, seed))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let dimension = self.state.take(1);
+        let (x, _) = sobol_2d(self.shuffled_index(dimension));
+        self.scramble(x, dimension)
+    }
+
+    fn get_2d(&mut self) -> Point2<f64> {
+        let dimension = self.state.take(2);
+        let (x, y) = sobol_2d(self.shuffled_index(dimension));
+        Point2::new(self.scramble(x, dimension), self.scramble(y, dimension + 1))
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// The side of the square blue noise mask, which tiles across the image
+const MASK_SIZE: usize = 32;
+
+// A tileable mask of values in (0, 1) where neighbouring pixels are as different as possible, built by
+// repeatedly filling the emptiest part of the mask. Each pixel's "energy" is how crowded it is by the
+// pixels already placed, falling off as a Gaussian of their distance across the wrapped tile.
+// REF: Ulichney, "The void-and-cluster method for dither array generation", SPIE 1993
+fn blue_noise_mask(seed: u64) -> Vec<f64> {
+    let n = MASK_SIZE * MASK_SIZE;
+    let kernel: Vec<f64> = (0..n)
+        .map(|i| {
+            let (dx, dy) = (i % MASK_SIZE, i / MASK_SIZE);
+            let (dx, dy) = (dx.min(MASK_SIZE - dx) as f64, dy.min(MASK_SIZE - dy) as f64);
+            (-(dx * dx + dy * dy) / (2.0 * 1.5 * 1.5)).exp()
+        })
+        .collect();
+
+    let mut on = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let toggle = |on: &mut Vec<bool>, energy: &mut Vec<f64>, p: usize| {
+        on[p] = !on[p];
+        let sign = if on[p] { 1.0 } else { -1.0 };
+        let (px, py) = (p % MASK_SIZE, p / MASK_SIZE);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % MASK_SIZE + MASK_SIZE - px) % MASK_SIZE;
+            let dy = (q / MASK_SIZE + MASK_SIZE - py) % MASK_SIZE;
+            *e += sign * kernel[dy * MASK_SIZE + dx];
+        }
+    };
+    // The most crowded placed pixel, or the emptiest free one
+    let tightest_cluster = |on: &[bool], energy: &[f64]| {
+        (0..n).filter(|&p| on[p]).max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap()).unwrap()
+    };
+    let largest_void = |on: &[bool], energy: &[f64]| {
+        (0..n).filter(|&p| !on[p]).min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap()).unwrap()
+    };
+
+    // Start from a random tenth of the pixels and even them out, moving the most crowded pixel to the
+    // emptiest spot until it would move straight back
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let initial = n / 10;
+    let mut placed = 0;
+    while placed < initial {
+        let p = rng.gen_range(0, n);
+        if !on[p] {
+            toggle(&mut on, &mut energy, p);
+            placed += 1;
+        }
+    }
+    for _ in 0..n {
+        let cluster = tightest_cluster(&on, &energy);
+        toggle(&mut on, &mut energy, cluster);
+        let void = largest_void(&on, &energy);
+        toggle(&mut on, &mut energy, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Rank the starting pixels by taking the most crowded away first, then rank the rest by filling
+    // the emptiest spot each time
+    let mut rank = vec![0; n];
+    let (mut removed_on, mut removed_energy) = (on.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let cluster = tightest_cluster(&removed_on, &removed_energy);
+        toggle(&mut removed_on, &mut removed_energy, cluster);
+        rank[cluster] = r;
+    }
+    for r in initial..n {
+        let void = largest_void(&on, &energy);
+        toggle(&mut on, &mut energy, void);
+        rank[void] = r;
+    }
+    rank.into_iter().map(|r| (r as f64 + 0.5) / n as f64).collect()
+}
+
+impl BlueNoiseSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> BlueNoiseSampler {
+        BlueNoiseSampler { samples_per_pixel, seed, mask: Arc::new(blue_noise_mask(seed)), state: SampleState::default() }
+    }
+
+    // The pixel's value in the mask. Each dimension looks the mask up at its own offset, so the
+    // dimensions' shifts are independent of each other but each is blue noise across the image.
+    fn shift(&self, dimension: u32) -> f64 {
+        let offset = hash(&[self.seed, dimension as u64]) as usize;
+        let x = (self.state.pixel.0 as usize + offset) % MASK_SIZE;
+        let y = (self.state.pixel.1 as usize + (offset >> 16)) % MASK_SIZE;
+        self.mask[y * MASK_SIZE + x]
+    }
+
+    // The sample's position in the sequence for this pair of dimensions, the same for every pixel
+    fn shuffled_index(&self, dimension: u32) -> u32 {
+        shuffle_in_runs(self.state.index, self.samples_per_pixel, hash(&[self.seed, dimension as u64]))
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let dimension = self.state.take(1);
+        let (x, _) = sobol_2d(self.shuffled_index(dimension));
+        wrap(fixed_to_unit(x) + self.shift(dimension))
+    }
+
+    fn get_2d(&mut self) -> Point2<f64> {
+        let dimension = self.state.take(2);
+        let (x, y) = sobol_2d(self.shuffled_index(dimension));
+        Point2::new(wrap(fixed_to_unit(x) + self.shift(dimension)), wrap(fixed_to_unit(y) + self.shift(dimension + 1)))
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// Wrap a sum of samples back into [0, 1)
+fn wrap(x: f64) -> f64 {
+    (x - x.floor()).min(ONE_MINUS_EPSILON)
+}
+
+// Mix a list of numbers into one well-scrambled 64-bit hash
+// REF: Steele, Lea and Flood, "Fast Splittable Pseudorandom Number Generators" (SplitMix64), OOPSLA 2014
+pub fn hash(values: &[u64]) -> u64 {
+    let mut h = 0x9e3779b97f4a7c15u64;
+    for &v in values {
+        h = (h ^ v).wrapping_add(0x9e3779b97f4a7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+        h ^= h >> 31;
+    }
+    h
+}
+
+// A hash as a number in [0, 1)
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Element `i` of a random permutation of 0..`length` chosen by `seed`, without building the permutation
+// REF: Kensler, "Correlated Multi-Jittered Sampling", Pixar Technical Memo 13-01, 2013
+fn permutation_element(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    // Shuffle within the next power of two up, repeating until the result lands inside the length
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % length
+}
+
+// Shuffle `index` within its run of `count` samples, each run in its own order chosen by `seed`. Samples
+// taken past a sampler's planned count carry on into the next run, so different pairs of dimensions still
+// see them in different orders rather than all at the same position in the sequence.
+fn shuffle_in_runs(index: u32, count: u32, seed: u64) -> u32 {
+    let count = count.max(1);
+    let run = index / count;
+    run * count + permutation_element(index % count, count, hash(&[seed, run as u64]) as u32)
+}
+
+#[test]
+fn test_samplers() {
+    let samplers: Vec<Box<dyn Sampler>> = vec![
+        Box::new(IndependentSampler::new(16, 7)),
+        Box::new(StratifiedSampler::new(16, 7)),
+        Box::new(HaltonSampler::new(16, 7)),
+        Box::new(SobolSampler::new(16, 7)),
+        Box::new(BlueNoiseSampler::new(16, 7)),
+    ];
+    let take = |sampler: &mut dyn Sampler, pixel: (u32, u32), index: u32| {
+        sampler.start_pixel_sample(pixel, index);
+        let p = sampler.get_2d();
+        vec![p.x, p.y, sampler.get_1d(), sampler.get_2d().x]
+    };
+
+    for sampler in &samplers {
+        let mut sampler = sampler.clone_sampler();
+        // Every dimension lies in [0, 1)
+        for index in 0..20 {
+            assert!(take(&mut *sampler, (3, 5), index).iter().all(|&v| (0.0..1.0).contains(&v)));
+        }
+        // The same sample twice gives the same numbers, and another pixel different ones
+        let first = take(&mut *sampler, (3, 5), 2);
+        take(&mut *sampler, (9, 1), 4);
+        assert_eq!(first, take(&mut *sampler, (3, 5), 2));
+        assert_ne!(first, take(&mut *sampler, (4, 5), 2));
+    }
+
+    // Stratified and Sobol samples put exactly one of 16 samples in each cell of a 4x4 grid, in every
+    // pair of dimensions
+    for sampler in &[&samplers[1], &samplers[3]] {
+        let mut sampler = sampler.clone_sampler();
+        let mut pixel_cells = [0; 16];
+        let mut lens_cells = [0; 16];
+        for index in 0..16 {
+            sampler.start_pixel_sample((3, 5), index);
+            let pixel = sampler.get_2d();
+            let lens = sampler.get_2d();
+            pixel_cells[(pixel.y * 4.0) as usize * 4 + (pixel.x * 4.0) as usize] += 1;
+            lens_cells[(lens.y * 4.0) as usize * 4 + (lens.x * 4.0) as usize] += 1;
+        }
+        assert_eq!(pixel_cells, [1; 16]);
+        assert_eq!(lens_cells, [1; 16]);
+    }
+
+    // Samples taken past the planned count, as adaptive sampling does, are still uncorrelated across
+    // dimensions: the pixel, lens and light choice land in each combination of halves equally often
+    for sampler in &samplers {
+        let mut sampler = sampler.clone_sampler();
+        let (mut lens_quadrants, mut choice_quadrants) = ([0; 4], [0; 4]);
+        for index in 16..16 + 4096 {
+            sampler.start_pixel_sample((3, 5), index);
+            let pixel = (sampler.get_2d().x * 2.0) as usize;
+            let lens = (sampler.get_2d().x * 2.0) as usize;
+            let choice = (sampler.get_1d() * 2.0) as usize;
+            lens_quadrants[pixel * 2 + lens] += 1;
+            choice_quadrants[pixel * 2 + choice] += 1;
+        }
+        for &count in lens_quadrants.iter().chain(&choice_quadrants) {
+            assert!((924..1124).contains(&count), "{:?} {:?}", lens_quadrants, choice_quadrants);
+        }
+    }
+}
+
+#[test]
+fn test_blue_noise_mask() {
+    let mask = blue_noise_mask(0);
+    // Every value is used exactly once
+    let mut ranks: Vec<usize> = mask.iter().map(|v| (v * mask.len() as f64) as usize).collect();
+    ranks.sort();
+    assert_eq!(ranks, (0..mask.len()).collect::<Vec<_>>());
+
+    // Neighbours differ by more than the 1/3 expected of white noise
+    let mut difference = 0.0;
+    for y in 0..MASK_SIZE {
+        for x in 0..MASK_SIZE {
+            let right = mask[y * MASK_SIZE + (x + 1) % MASK_SIZE];
+            let below = mask[(y + 1) % MASK_SIZE * MASK_SIZE + x];
+            difference += (mask[y * MASK_SIZE + x] - right).abs() + (mask[y * MASK_SIZE + x] - below).abs();
+        }
+    }
+    assert!(difference / (2 * MASK_SIZE * MASK_SIZE) as f64 > 0.4);
+}